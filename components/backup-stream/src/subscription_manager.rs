@@ -1,6 +1,13 @@
 // Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use engine_traits::KvEngine;
 use error_code::ErrorCodeExt;
@@ -8,6 +15,7 @@ use futures::FutureExt;
 use kvproto::metapb::Region;
 use pd_client::PdClient;
 use raft::StateRole;
+use rand::Rng;
 use raftstore::{
     coprocessor::{ObserveHandle, RegionInfoProvider},
     store::{fsm::ChangeObserver, SignificantRouter},
@@ -15,9 +23,10 @@ use raftstore::{
 use resolved_ts::LeadershipResolver;
 use tikv::storage::Statistics;
 use tikv_util::{
-    box_err, debug, info, sys::thread::ThreadBuildWrapper, time::Instant, warn, worker::Scheduler,
+    box_err, debug, info, memory::MemoryQuota, sys::thread::ThreadBuildWrapper, time::Instant,
+    warn, worker::Scheduler,
 };
-use tokio::sync::mpsc::{channel, error::SendError, Receiver, Sender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use txn_types::TimeStamp;
 
 use crate::{
@@ -32,7 +41,7 @@ use crate::{
     router::{Router, TaskSelector},
     subscription_track::{CheckpointType, ResolveResult, SubscriptionTracer},
     try_send,
-    utils::{self, FutureWaitGroup, Work},
+    utils::{self, CallbackWaitGroup, Work},
     Task,
 };
 
@@ -46,21 +55,176 @@ const TRY_START_OBSERVE_MAX_RETRY_TIME: u8 = 24;
 const RETRY_AWAIT_BASIC_DURATION: Duration = Duration::from_secs(1);
 const RETRY_AWAIT_MAX_DURATION: Duration = Duration::from_secs(16);
 
-fn backoff_for_start_observe(failed_for: u8) -> Duration {
+/// Picks the next retry delay via decorrelated jitter (as described in
+/// AWS's "Exponential Backoff And Jitter" post), given the delay the
+/// *previous* attempt slept for: `min(cap, random_between(base, prev *
+/// 3))`. This still grows geometrically in expectation, same as the old
+/// plain doubling, but because the draw is uniform over a range that
+/// depends on `prev` rather than a fixed per-attempt value, regions that
+/// started failing around the same time (e.g. a store losing its PD
+/// connection) spread their retries out instead of waking in lockstep.
+fn backoff_for_start_observe(prev: Duration) -> Duration {
+    let upper = Ord::max(prev.saturating_mul(3), RETRY_AWAIT_BASIC_DURATION);
     Ord::min(
-        RETRY_AWAIT_BASIC_DURATION * (1 << failed_for),
+        rand::thread_rng().gen_range(RETRY_AWAIT_BASIC_DURATION..=upper),
         RETRY_AWAIT_MAX_DURATION,
     )
 }
 
+/// How many times a PD/meta call is retried, reconnecting in between, before
+/// its error is finally surfaced to the caller.
+const LEADER_CHANGE_RETRY: usize = 3;
+
+/// Runs `f`, and if it fails, triggers a PD reconnect and tries again, up to
+/// `LEADER_CHANGE_RETRY` times. Modeled on the `retry!` macro in
+/// tikv-client's PD module: a PD leader change usually surfaces as a
+/// handful of back-to-back RPC failures that clear up once the client has
+/// reconnected to the new leader, and treating it like any other failure
+/// would needlessly burn into whatever retry budget the caller tracks for
+/// itself (e.g. `has_failed_for` for observe starts).
+async fn retry_on_leader_change<PDC, T, F, Fut>(pd_client: &PDC, mut f: F) -> Result<T>
+where
+    PDC: PdClient,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(err) if attempt < LEADER_CHANGE_RETRY => {
+                warn!("pd/meta call failed, retrying after a reconnect"; "attempt" => attempt, "err" => %err);
+                metrics::PD_RECONNECT_COUNT.inc();
+                if let Err(e) = pd_client.reconnect() {
+                    warn!("failed to reconnect to pd, will still retry the call"; "err" => %e);
+                }
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How urgently a [`ScanCmd`] should be serviced by the scan pool's
+/// dispatcher, highest first. A region that just failed its observe start
+/// (`Retry`) is already behind on its RPO budget and blocking other
+/// operations on it, so it jumps ahead of a region whose leader merely
+/// changed (`Refresh`), which in turn jumps ahead of a brand-new
+/// subscription that hasn't started accruing backlog yet (`Initial`).
+///
+/// The ordering here is load-bearing: `next_scan_cmd` picks the
+/// highest-valued non-empty queue first, so don't reorder these variants
+/// without updating it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ScanCmdPriority {
+    Initial,
+    Refresh,
+    Retry,
+}
+
+/// How many classes [`ScanCmdPriority`] has; used to size the dispatcher's
+/// per-class queues.
+const SCAN_CMD_PRIORITY_CLASSES: usize = 3;
+
 /// a request for doing initial scanning.
 struct ScanCmd {
     region: Region,
     handle: ObserveHandle,
     last_checkpoint: TimeStamp,
+    priority: ScanCmdPriority,
     _work: Work,
 }
 
+/// See [`next_scan_cmd`].
+const SCAN_PRIORITY_STARVATION_GUARD: u32 = 8;
+
+/// Pulls the next [`ScanCmd`] a scan-pool worker should run off the shared,
+/// per-priority MPMC queues. Every worker calls this directly on the same
+/// `flume` receivers instead of going through a single dispatcher, so an
+/// idle worker "steals" whichever command is ready rather than waiting on
+/// a central loop to hand it one.
+///
+/// Prefers the highest-priority non-empty class, except it forces a lower
+/// one through once every [`SCAN_PRIORITY_STARVATION_GUARD`] picks (tracked
+/// per-worker in `streak`) so a sustained burst of `Retry`/`Refresh`
+/// commands can't starve `Initial` subscriptions out indefinitely. Returns
+/// `None` once every sender has been dropped, i.e. the pool is shutting
+/// down.
+async fn next_scan_cmd(
+    queues: &[flume::Receiver<ScanCmd>; SCAN_CMD_PRIORITY_CLASSES],
+    streak: &mut u32,
+) -> Option<ScanCmd> {
+    if *streak >= SCAN_PRIORITY_STARVATION_GUARD {
+        if let Some(cmd) = queues.iter().find_map(|q| q.try_recv().ok()) {
+            *streak = 0;
+            return Some(cmd);
+        }
+    }
+    for (idx, q) in queues.iter().enumerate().rev() {
+        if let Ok(cmd) = q.try_recv() {
+            *streak = if idx == 0 { 0 } else { *streak + 1 };
+            return Some(cmd);
+        }
+    }
+    // Nothing is ready right now; block until some class gets a command,
+    // still biased toward servicing the highest priority first.
+    tokio::select! {
+        biased;
+        Ok(cmd) = queues[2].recv_async() => {
+            *streak += 1;
+            Some(cmd)
+        }
+        Ok(cmd) = queues[1].recv_async() => {
+            *streak += 1;
+            Some(cmd)
+        }
+        Ok(cmd) = queues[0].recv_async() => {
+            *streak = 0;
+            Some(cmd)
+        }
+        else => None,
+    }
+}
+
+/// A reported mismatch between an expected run of events and what was
+/// actually recorded, produced by [`match_event_prefix`].
+#[derive(Debug, Eq, PartialEq)]
+pub struct EventMismatch<'a, T> {
+    /// How many leading events of `pat` matched before the first divergence
+    /// (or before `slice` ran out).
+    pub matched: usize,
+    /// The unmatched tail of the expected pattern.
+    pub expected: &'a [T],
+    /// The unmatched tail of the recorded events.
+    pub actual: &'a [T],
+}
+
+/// Bounds-checked replacement for `&slice[pat.len()..]`-style prefix
+/// matching over a recorded event sequence. Returns the remainder of
+/// `slice` once every element of `pat` is consumed, or a structured
+/// [`EventMismatch`] describing where the two diverged -- instead of the
+/// `index out of bounds` panic that ad-hoc slicing produces once `slice` is
+/// shorter than `pat`. Safe to call from production code that wants to
+/// validate an observed event ordering, not just from tests.
+pub fn match_event_prefix<'a, T: PartialEq>(
+    slice: &'a [T],
+    pat: &'a [T],
+) -> std::result::Result<&'a [T], EventMismatch<'a, T>> {
+    let matched = slice
+        .iter()
+        .zip(pat.iter())
+        .take_while(|(have, want)| have == want)
+        .count();
+    if matched < pat.len() {
+        return Err(EventMismatch {
+            matched,
+            expected: &pat[matched..],
+            actual: &slice[matched..],
+        });
+    }
+    Ok(&slice[matched..])
+}
+
 /// The response of requesting resolve the new checkpoint of regions.
 pub struct ResolvedRegions {
     items: Vec<ResolveResult>,
@@ -195,13 +359,12 @@ impl ScanCmd {
     /// execute the command, when meeting error, retrying.
     async fn exec_by_with_retry(self, init: impl InitialScan) {
         let mut retry_time = TRY_START_OBSERVE_MAX_RETRY_TIME;
+        let mut prev_backoff = RETRY_AWAIT_BASIC_DURATION;
         loop {
             match self.exec_by(init.clone()).await {
                 Err(err) if should_retry(&err) && retry_time > 0 => {
-                    tokio::time::sleep(backoff_for_start_observe(
-                        TRY_START_OBSERVE_MAX_RETRY_TIME - retry_time,
-                    ))
-                    .await;
+                    prev_backoff = backoff_for_start_observe(prev_backoff);
+                    tokio::time::sleep(prev_backoff).await;
                     warn!("meet retryable error"; "err" => %err, "retry_time" => retry_time);
                     retry_time -= 1;
                     continue;
@@ -217,8 +380,215 @@ impl ScanCmd {
     }
 }
 
-async fn scan_executor_loop(init: impl InitialScan, mut cmds: Receiver<ScanCmd>) {
-    while let Some(cmd) = cmds.recv().await {
+/// How many of the most recent scans' durations to average over when
+/// deciding how long to throttle the next one.
+const TRANQUILIZER_WINDOW_SIZE: usize = 8;
+
+/// Default value of the `tranquility` knob in `BackupStreamConfig`: no
+/// throttling, so the scan pool behaves exactly as it did before this knob
+/// existed until an operator opts in.
+const DEFAULT_TRANQUILITY: u32 = 0;
+
+/// Converts the `tranquility` config knob into the busy ratio the
+/// `Tranquilizer` targets. `tranquility` is "how many scans' worth of rest
+/// to take for every scan's worth of work", so the pool should spend
+/// `1 / (1 + tranquility)` of its time busy; operators can also retune the
+/// resulting ratio at runtime via
+/// `RegionSubscriptionManager::set_target_busy_ratio`.
+fn busy_ratio_from_tranquility(tranquility: u32) -> f64 {
+    1.0 / (1.0 + tranquility as f64)
+}
+
+/// Rough upper bound on how much lock/write/default CF data a single
+/// region's initial scan can buffer before it's streamed out. Not an exact
+/// measurement (the real size is only known once the scan finishes); it
+/// only sizes the memory-quota reservation `ScanPoolHandle::request` takes
+/// before a `ScanCmd` is allowed onto the queue.
+const ESTIMATED_SCAN_MEMORY_PER_REGION: usize = 8 * 1024 * 1024;
+
+/// Default ceiling on how much memory queued-but-unfinished initial scans
+/// may reserve at once; see `ScanPoolHandle`'s `memory_quota` field.
+const DEFAULT_SCAN_MEMORY_QUOTA: usize = 1024 * 1024 * 1024;
+
+/// Adaptive IO throttling for the initial-scan pool.
+///
+/// Initial scans are IO-heavy enough to starve foreground raftstore traffic
+/// if the pool just fires off tasks as fast as the channel drains. This
+/// keeps a short sliding window of how long recent scans actually took and,
+/// after each one, sleeps long enough to bring the pool's measured busy
+/// ratio back down to `target_busy_ratio` of wall time. Capping the busy
+/// ratio (rather than the thread count) caps a large scan's blast radius
+/// without needing to guess a good worker count up front — the same idea
+/// Garage's block manager uses to throttle its background repair/resync
+/// workers.
+struct Tranquilizer {
+    target_busy_ratio: Arc<AtomicU64>,
+    window: VecDeque<Duration>,
+}
+
+impl Tranquilizer {
+    fn new(target_busy_ratio: Arc<AtomicU64>) -> Self {
+        Self {
+            target_busy_ratio,
+            window: VecDeque::with_capacity(TRANQUILIZER_WINDOW_SIZE),
+        }
+    }
+
+    fn target_busy_ratio(&self) -> f64 {
+        f64::from_bits(self.target_busy_ratio.load(Ordering::Relaxed))
+    }
+
+    /// Records how long the last scan took, and sleeps long enough to keep
+    /// the scan pool's average busy ratio at `target_busy_ratio`.
+    async fn tranquil(&mut self, busy_time: Duration) {
+        if self.window.len() == TRANQUILIZER_WINDOW_SIZE {
+            self.window.pop_front();
+        }
+        self.window.push_back(busy_time);
+        let avg_busy_time = self.window.iter().sum::<Duration>() / self.window.len() as u32;
+
+        let target_busy_ratio = self.target_busy_ratio();
+        if !(target_busy_ratio > 0.0 && target_busy_ratio < 1.0) {
+            // A ratio outside (0, 1) disables throttling: 0 would mean an
+            // infinite sleep, 1 (or anything beyond) means "never throttle".
+            return;
+        }
+        let sleep_time = avg_busy_time.mul_f64(1.0 / target_busy_ratio - 1.0);
+        if sleep_time > Duration::ZERO {
+            // Assumes a dedicated histogram and counter for this exist in
+            // `crate::metrics`; that registration lives outside this
+            // checkout.
+            metrics::INITIAL_SCAN_THROTTLE_DURATION.observe(sleep_time.as_secs_f64());
+            metrics::INITIAL_SCAN_THROTTLE_DURATION_SEC_TOTAL.inc_by(sleep_time.as_secs_f64());
+            tokio::time::sleep(sleep_time).await;
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A single outstanding "failed to start observe" retry.
+///
+/// This mirrors the state already carried by the in-flight
+/// `tokio::spawn`ed backoff timer in `start_observe_with_failure_count`
+/// into durable storage, so a restart does not silently forget about it
+/// and extend RPO for the region it covers.
+#[derive(Clone, Debug)]
+struct RetryQueueEntry {
+    region_id: u64,
+    has_failed_for: u8,
+    /// The delay this entry's *last* scheduled attempt slept for, in
+    /// milliseconds. Seeds the decorrelated-jitter draw for the next
+    /// attempt (see `backoff_for_start_observe`) so a restart resumes the
+    /// same spread-out schedule instead of re-synchronizing every
+    /// recovered retry onto `RETRY_AWAIT_BASIC_DURATION`.
+    prev_backoff_millis: u64,
+    /// Milliseconds since `UNIX_EPOCH` at which this entry becomes
+    /// eligible for another attempt. Stored as wall-clock time, not a
+    /// `std::time::Instant`, because it has to survive a restart.
+    next_attempt_millis: u64,
+}
+
+/// Durable mirror of the observe-retry backoff state, modeled on Garage's
+/// resync queue: every time a retry is scheduled it is persisted through
+/// `MetadataClient`, and the entry is removed once the observe it is
+/// guarding eventually succeeds (or turns out to be moot). `start` reloads
+/// whatever a previous process left outstanding before it begins handling
+/// live operations, so an unplanned restart no longer drops in-flight
+/// retries and silently extends the RPO for the regions they cover.
+struct PersistentRetryQueue<S> {
+    meta_cli: MetadataClient<S>,
+    depth: AtomicU64,
+}
+
+impl<S: MetaStore + 'static> PersistentRetryQueue<S> {
+    fn new(meta_cli: MetadataClient<S>) -> Self {
+        Self {
+            meta_cli,
+            depth: AtomicU64::new(0),
+        }
+    }
+
+    fn depth(&self) -> u64 {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Persists a retry so it survives a restart.
+    async fn schedule(&self, entry: RetryQueueEntry) {
+        if let Err(err) = self
+            .meta_cli
+            .upsert_observe_retry(
+                entry.region_id,
+                entry.has_failed_for,
+                entry.prev_backoff_millis,
+                entry.next_attempt_millis,
+            )
+            .await
+        {
+            warn!("failed to persist observe retry, it won't survive a restart";
+                "region_id" => entry.region_id, "err" => %err);
+            return;
+        }
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics::PENDING_OBSERVE_RETRIES.set(depth as i64);
+    }
+
+    /// Removes a persisted retry once the observe it was guarding
+    /// succeeds. A no-op if nothing was persisted for `region_id`.
+    async fn complete(&self, region_id: u64) {
+        match self.meta_cli.remove_observe_retry(region_id).await {
+            Ok(true) => {
+                let depth = self.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+                metrics::PENDING_OBSERVE_RETRIES.set(depth as i64);
+            }
+            Ok(false) => {}
+            Err(err) => {
+                warn!("failed to remove persisted observe retry"; "region_id" => region_id, "err" => %err)
+            }
+        }
+    }
+
+    /// Loads every retry left outstanding by a previous process.
+    async fn reload(&self) -> Result<Vec<RetryQueueEntry>> {
+        let raw = self.meta_cli.list_observe_retries().await?;
+        let entries: Vec<RetryQueueEntry> = raw
+            .into_iter()
+            .map(
+                |(region_id, has_failed_for, prev_backoff_millis, next_attempt_millis)| {
+                    RetryQueueEntry {
+                        region_id,
+                        has_failed_for,
+                        prev_backoff_millis,
+                        next_attempt_millis,
+                    }
+                },
+            )
+            .collect();
+        self.depth.store(entries.len() as u64, Ordering::Relaxed);
+        metrics::PENDING_OBSERVE_RETRIES.set(entries.len() as i64);
+        Ok(entries)
+    }
+}
+
+/// One of the `number` concurrent consumers spawned by
+/// `spawn_executors_with_ratio`, each pulling directly off the shared
+/// per-priority `flume` queues via [`next_scan_cmd`] rather than waiting on
+/// a single dispatcher. `tranquilizer` is shared across every worker in the
+/// pool so the busy-ratio throttle it enforces reflects the pool's
+/// aggregate load, not just this worker's.
+async fn scan_worker_loop(
+    init: impl InitialScan,
+    queues: [flume::Receiver<ScanCmd>; SCAN_CMD_PRIORITY_CLASSES],
+    tranquilizer: Arc<tokio::sync::Mutex<Tranquilizer>>,
+    memory_quota: Arc<MemoryQuota>,
+) {
+    let mut streak = 0;
+    while let Some(cmd) = next_scan_cmd(&queues, &mut streak).await {
         debug!("handling initial scan request"; "region_id" => %cmd.region.get_id());
         metrics::PENDING_INITIAL_SCAN_LEN
             .with_label_values(&["queuing"])
@@ -233,46 +603,178 @@ async fn scan_executor_loop(init: impl InitialScan, mut cmds: Receiver<ScanCmd>)
         }
 
         let init = init.clone();
+        let tranquilizer = Arc::clone(&tranquilizer);
+        let memory_quota = Arc::clone(&memory_quota);
         tokio::task::spawn(async move {
             metrics::PENDING_INITIAL_SCAN_LEN
                 .with_label_values(&["executing"])
                 .inc();
+            let begin = Instant::now_coarse();
             cmd.exec_by_with_retry(init).await;
             metrics::PENDING_INITIAL_SCAN_LEN
                 .with_label_values(&["executing"])
                 .dec();
+            memory_quota.free(ESTIMATED_SCAN_MEMORY_PER_REGION);
+            metrics::INITIAL_SCAN_MEMORY_QUOTA_IN_USE.set(memory_quota.in_use() as i64);
+            tranquilizer
+                .lock()
+                .await
+                .tranquil(begin.saturating_elapsed())
+                .await;
         });
     }
 }
 
-/// spawn the executors in the scan pool.
+/// How often the scan pool's own runtime metrics are sampled and
+/// republished as gauges; see [`sample_scan_pool_metrics`].
+#[cfg(tokio_unstable)]
+const SCAN_POOL_METRICS_SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically samples the scan pool runtime's own task/worker metrics
+/// and publishes them alongside the rest of `metrics::*`, so a stalled or
+/// saturated pool is visible before `scan_pool_handle.request` itself
+/// starts returning slowly. Only compiled in when the binary is built with
+/// `--cfg tokio_unstable` (the runtime only tracks these counters then);
+/// otherwise this is a no-op that never wakes up, so the default build
+/// pays nothing for it.
+#[cfg(tokio_unstable)]
+async fn sample_scan_pool_metrics(handle: tokio::runtime::Handle) {
+    let mut interval = tokio::time::interval(SCAN_POOL_METRICS_SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let rt = handle.metrics();
+        metrics::SCAN_POOL_WORKERS.set(rt.num_workers() as i64);
+        metrics::SCAN_POOL_ALIVE_TASKS.set(rt.num_alive_tasks() as i64);
+        metrics::SCAN_POOL_GLOBAL_QUEUE_DEPTH.set(rt.global_queue_depth() as i64);
+        metrics::SCAN_POOL_BLOCKING_QUEUE_DEPTH.set(rt.blocking_queue_depth() as i64);
+        let poll_count = rt.poll_count();
+        metrics::SCAN_POOL_POLL_COUNT_TOTAL.set(poll_count as i64);
+        let mean_poll_duration_sec = if poll_count > 0 {
+            rt.total_busy_duration().as_secs_f64() / poll_count as f64
+        } else {
+            0.0
+        };
+        metrics::SCAN_POOL_MEAN_POLL_DURATION_SEC.set(mean_poll_duration_sec);
+    }
+}
+
+#[cfg(not(tokio_unstable))]
+async fn sample_scan_pool_metrics(_handle: tokio::runtime::Handle) {}
+
+/// spawn the executors in the scan pool, throttled to the default busy
+/// ratio and memory quota. Callers that want to tune those at runtime
+/// (e.g. `RegionSubscriptionManager`) should use
+/// [`spawn_executors_with_ratio`] instead and hang on to the `Arc`s they
+/// pass in.
 fn spawn_executors(
     init: impl InitialScan + Send + Sync + 'static,
     number: usize,
 ) -> ScanPoolHandle {
-    let (tx, rx) = tokio::sync::mpsc::channel(MESSAGE_BUFFER_SIZE);
+    let target_busy_ratio = Arc::new(AtomicU64::new(
+        busy_ratio_from_tranquility(DEFAULT_TRANQUILITY).to_bits(),
+    ));
+    let memory_quota = Arc::new(MemoryQuota::new(DEFAULT_SCAN_MEMORY_QUOTA));
+    spawn_executors_with_ratio(init, number, target_busy_ratio, memory_quota)
+}
+
+/// Like [`spawn_executors`], but throttles the pool to `target_busy_ratio`
+/// and `memory_quota` instead of the defaults, and lets the caller keep
+/// tuning them at runtime by storing its own reference to the same `Arc`s.
+fn spawn_executors_with_ratio(
+    init: impl InitialScan + Send + Sync + 'static,
+    number: usize,
+    target_busy_ratio: Arc<AtomicU64>,
+    memory_quota: Arc<MemoryQuota>,
+) -> ScanPoolHandle {
+    // One bounded MPMC channel per priority class, shared by every worker
+    // below: `flume`'s receivers are freely cloneable and safe to poll
+    // concurrently, so this is what lets idle workers steal whichever
+    // command is ready instead of queuing behind a single dispatcher.
+    let (tx_initial, rx_initial) = flume::bounded(MESSAGE_BUFFER_SIZE);
+    let (tx_refresh, rx_refresh) = flume::bounded(MESSAGE_BUFFER_SIZE);
+    let (tx_retry, rx_retry) = flume::bounded(MESSAGE_BUFFER_SIZE);
+    let queues = [rx_initial, rx_refresh, rx_retry];
+
     let pool = create_scan_pool(number);
-    pool.spawn(async move {
-        scan_executor_loop(init, rx).await;
-    });
-    ScanPoolHandle { tx, _pool: pool }
+    let tranquilizer = Arc::new(tokio::sync::Mutex::new(Tranquilizer::new(target_busy_ratio)));
+    for _ in 0..number {
+        let init = init.clone();
+        let queues = queues.clone();
+        let tranquilizer = Arc::clone(&tranquilizer);
+        let executor_memory_quota = Arc::clone(&memory_quota);
+        pool.spawn(async move {
+            scan_worker_loop(init, queues, tranquilizer, executor_memory_quota).await;
+        });
+    }
+    pool.spawn(sample_scan_pool_metrics(pool.handle().clone()));
+    ScanPoolHandle {
+        queues: [tx_initial, tx_refresh, tx_retry],
+        _pool: pool,
+        memory_quota,
+    }
+}
+
+/// Why a `ScanCmd` wasn't admitted onto the scan pool's queue.
+enum ScanCmdRejected {
+    /// The pool has shut down; `exec_by_with_retry` will never run for it.
+    PoolClosed(flume::SendError<ScanCmd>),
+    /// The memory quota guarding the queue is exhausted; the command wasn't
+    /// sent and can be retried once some in-flight scans finish.
+    QuotaExceeded(ScanCmd),
 }
 
 struct ScanPoolHandle {
-    // Theoretically, we can get rid of the sender, and spawn a new task via initial loader in each
-    // thread. But that will make `SubscribeManager` holds a reference to the implementation of
-    // `InitialScan`, which will get the type information a mass.
-    tx: Sender<ScanCmd>,
+    // One sender per `ScanCmdPriority`, indexed by `priority as usize`; see
+    // `next_scan_cmd` for how workers pull from the matching receivers.
+    queues: [flume::Sender<ScanCmd>; SCAN_CMD_PRIORITY_CLASSES],
 
     _pool: ScanPool,
+
+    // Bounds how much memory queued-but-not-yet-finished initial scans may
+    // reserve; acquired here before a `ScanCmd` is enqueued and released by
+    // `scan_worker_loop` once the scan it guards finishes, giving the
+    // initial-scan path the same bounded-memory guarantee Garage applies to
+    // its block transfer workers.
+    memory_quota: Arc<MemoryQuota>,
 }
 
 impl ScanPoolHandle {
-    async fn request(&self, cmd: ScanCmd) -> std::result::Result<(), SendError<ScanCmd>> {
+    async fn request(&self, cmd: ScanCmd) -> std::result::Result<(), ScanCmdRejected> {
+        if !self.memory_quota.alloc(ESTIMATED_SCAN_MEMORY_PER_REGION) {
+            metrics::INITIAL_SCAN_MEMORY_QUOTA_REJECTED.inc();
+            return Err(ScanCmdRejected::QuotaExceeded(cmd));
+        }
+        metrics::INITIAL_SCAN_MEMORY_QUOTA_IN_USE.set(self.memory_quota.in_use() as i64);
         metrics::PENDING_INITIAL_SCAN_LEN
             .with_label_values(&["queuing"])
             .inc();
-        self.tx.send(cmd).await
+        self.queues[cmd.priority as usize]
+            .send_async(cmd)
+            .await
+            .map_err(|err| {
+                self.memory_quota.free(ESTIMATED_SCAN_MEMORY_PER_REGION);
+                ScanCmdRejected::PoolClosed(err)
+            })
+    }
+
+    /// Tears the scan pool's runtime down, blocking the calling task for up
+    /// to `timeout` while its worker threads finish whatever they're doing.
+    /// A no-op (besides a log line) if some other clone of the handle is
+    /// still alive, since a `tokio::runtime::Runtime` can only be shut down
+    /// by its sole owner.
+    async fn shutdown(self: Arc<Self>, timeout: Duration) {
+        match Arc::try_unwrap(self) {
+            Ok(handle) => {
+                tokio::task::spawn_blocking(move || handle._pool.shutdown_timeout(timeout))
+                    .await
+                    .unwrap_or_else(|err| {
+                        warn!("scan pool shutdown task panicked"; "err" => %err)
+                    });
+            }
+            Err(_) => {
+                warn!("scan pool handle still has outstanding references, skipping runtime shutdown");
+            }
+        }
     }
 }
 
@@ -293,19 +795,19 @@ pub struct RegionSubscriptionManager<S, R, PDC> {
     scheduler: Scheduler<Task>,
     observer: BackupStreamObserver,
     subs: SubscriptionTracer,
+    // Stored as bits of an `f64` so it can be retuned at runtime via
+    // `set_target_busy_ratio` without needing a lock.
+    target_busy_ratio: Arc<AtomicU64>,
+    // Durable record of outstanding "failed to start observe" retries; see
+    // `PersistentRetryQueue`.
+    retry_queue: Arc<PersistentRetryQueue<S>>,
+    // Set by `shutdown` so `request` stops admitting new `ObserveOp`s while
+    // the operator loop drains whatever is already in flight.
+    draining: Arc<AtomicBool>,
 
-<<<<<<< HEAD
     messenger: Sender<ObserveOp>,
     scan_pool_handle: Arc<ScanPoolHandle>,
     scans: Arc<CallbackWaitGroup>,
-=======
-    failure_count: HashMap<u64, u8>,
-    memory_manager: Arc<MemoryQuota>,
-
-    messenger: WeakSender<ObserveOp>,
-    scan_pool_handle: ScanPoolHandle,
-    scans: Arc<FutureWaitGroup>,
->>>>>>> 81d62b2e0e (log_backup: make a more rusty `CallbackWaitGroup` (#16740))
 }
 
 impl<S, R, PDC> Clone for RegionSubscriptionManager<S, R, PDC>
@@ -324,6 +826,9 @@ where
             scheduler: self.scheduler.clone(),
             observer: self.observer.clone(),
             subs: self.subs.clone(),
+            target_busy_ratio: Arc::clone(&self.target_busy_ratio),
+            retry_queue: Arc::clone(&self.retry_queue),
+            draining: Arc::clone(&self.draining),
             messenger: self.messenger.clone(),
             scan_pool_handle: self.scan_pool_handle.clone(),
             scans: CallbackWaitGroup::new(),
@@ -353,6 +858,11 @@ where
 {
     /// create a [`RegionSubscriptionManager`].
     ///
+    /// `tranquility` is the initial-scan throttling knob from
+    /// `BackupStreamConfig`: the pool rests `tranquility` scans' worth of
+    /// time for every scan's worth of work it does. `0` disables
+    /// throttling. Can be retuned afterwards via `set_target_busy_ratio`.
+    ///
     /// # returns
     ///
     /// a two-tuple, the first is the handle to the manager, the second is the
@@ -365,13 +875,24 @@ where
         pd_client: Arc<PDC>,
         scan_pool_size: usize,
         leader_checker: LeadershipResolver,
+        tranquility: u32,
     ) -> (Self, future![()])
     where
         E: KvEngine,
         HInit: SignificantRouter<E> + Clone + Sync + 'static,
     {
         let (tx, rx) = channel(MESSAGE_BUFFER_SIZE);
-        let scan_pool_handle = spawn_executors(initial_loader.clone(), scan_pool_size);
+        let target_busy_ratio = Arc::new(AtomicU64::new(
+            busy_ratio_from_tranquility(tranquility).to_bits(),
+        ));
+        let memory_quota = Arc::new(MemoryQuota::new(DEFAULT_SCAN_MEMORY_QUOTA));
+        let scan_pool_handle = spawn_executors_with_ratio(
+            initial_loader.clone(),
+            scan_pool_size,
+            Arc::clone(&target_busy_ratio),
+            memory_quota,
+        );
+        let retry_queue = Arc::new(PersistentRetryQueue::new(meta_cli.clone()));
         let op = Self {
             regions,
             meta_cli,
@@ -380,26 +901,39 @@ where
             scheduler: initial_loader.scheduler.clone(),
             observer,
             subs: initial_loader.tracing,
-<<<<<<< HEAD
+            target_busy_ratio,
+            retry_queue,
+            draining: Arc::new(AtomicBool::new(false)),
             messenger: tx,
             scan_pool_handle: Arc::new(scan_pool_handle),
             scans: CallbackWaitGroup::new(),
-=======
-            messenger: tx.downgrade(),
-            scan_pool_handle,
-            scans: FutureWaitGroup::new(),
-            failure_count: HashMap::new(),
-            memory_manager: Arc::clone(&initial_loader.quota),
->>>>>>> 81d62b2e0e (log_backup: make a more rusty `CallbackWaitGroup` (#16740))
         };
         let fut = op.clone().region_operator_loop(rx, leader_checker);
         (op, fut)
     }
 
+    /// Retunes the fraction of wall time the initial-scan pool is allowed
+    /// to spend busy. Takes effect on the next scan each pool worker picks
+    /// up; in-flight scans aren't interrupted.
+    pub fn set_target_busy_ratio(&self, ratio: f64) {
+        self.target_busy_ratio
+            .store(ratio.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Retunes how much memory queued-but-unfinished initial scans may
+    /// reserve in total; see `ScanPoolHandle`'s `memory_quota` field.
+    pub fn set_scan_memory_quota_capacity(&self, bytes: usize) {
+        self.scan_pool_handle.memory_quota.set_capacity(bytes);
+    }
+
     /// send an operation request to the manager.
     /// the returned future would be resolved after send is success.
     /// the opeartion would be executed asynchronously.
     pub async fn request(&self, op: ObserveOp) {
+        if self.draining.load(Ordering::Acquire) {
+            warn!("rejecting region op, the subscription manager is shutting down"; "op" => ?op);
+            return;
+        }
         if let Err(err) = self.messenger.send(op).await {
             annotate!(err, "BUG: region operator channel closed.")
                 .report("when executing region op");
@@ -408,9 +942,75 @@ where
 
     /// wait initial scanning get finished.
     pub async fn wait(&self, timeout: Duration) -> bool {
-        tokio::time::timeout(timeout, self.scans.wait())
+        self.wait_deadline(tokio::time::Instant::now() + timeout)
+            .await
+    }
+
+    /// Like [`Self::wait`], but bounded by a wall-clock deadline instead of
+    /// a relative timeout, so a caller that already keeps its own clock
+    /// (e.g. to align with a checkpoint tick) doesn't need to re-derive a
+    /// duration from it. When the deadline is hit, logs every region the
+    /// `SubscriptionTracer` currently holds (`current_regions`); that list
+    /// is not narrowed down to the regions whose initial scan is still
+    /// running.
+    pub async fn wait_deadline(&self, deadline: tokio::time::Instant) -> bool {
+        let timed_out = tokio::time::timeout_at(deadline, self.scans.wait())
             .map(move |result| result.is_err())
+            .await;
+        if timed_out {
+            warn!("timed out waiting for initial scans to finish";
+                "still_pending" => ?self.subs.current_regions());
+        }
+        timed_out
+    }
+
+    /// Gracefully quiesces the manager: stop admitting new `ObserveOp`s,
+    /// wait out whatever initial scans are already in flight, resolve once
+    /// more so the persisted checkpoint reflects that work, then stop the
+    /// operator loop. Mirrors the explicit shutdown ordering Veilid's
+    /// attachment manager uses, so a rolling restart doesn't truncate a
+    /// checkpoint mid-resolve.
+    ///
+    /// The first wait only covers scans tracked by this instance's own
+    /// `scans` group (every clone starts with an empty one); scans started
+    /// by the operator loop are waited for, up to 5 seconds, by the final
+    /// resolve. The scan pool's runtime is not torn down here:
+    /// `ScanPoolHandle::shutdown` only does that when it holds the last
+    /// reference to the handle, and `self` still holds one, so the runtime
+    /// stays up until every clone of the manager has been dropped.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::Release);
+        if self.wait(timeout).await {
+            warn!("timed out waiting for in-flight initial scans before shutdown"; "timeout" => ?timeout);
+        }
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        if let Err(err) = self
+            .messenger
+            .send(ObserveOp::ResolveRegions {
+                callback: Box::new(move |result| {
+                    let _ = tx.send(result);
+                }),
+                min_ts: TimeStamp::max(),
+            })
             .await
+        {
+            warn!("failed to request final resolve before shutdown"; "err" => %err);
+        } else if rx.await.is_err() {
+            warn!("final resolve before shutdown was dropped without a reply");
+        }
+
+        // Dropping our own sender doesn't close the channel: the operator
+        // loop's `self` holds a clone of it too. Telling it to shut down
+        // directly is what makes `region_operator_loop` return and drop the
+        // receiver, so the channel is actually closed afterwards and any
+        // straggling `request` fails fast instead of filling an unread
+        // channel.
+        if let Err(err) = self.messenger.send(ObserveOp::Shutdown).await {
+            warn!("operator loop already gone while shutting down"; "err" => %err);
+        }
+
+        Arc::clone(&self.scan_pool_handle).shutdown(timeout).await;
     }
 
     /// the handler loop.
@@ -419,12 +1019,18 @@ where
         mut message_box: Receiver<ObserveOp>,
         mut leader_checker: LeadershipResolver,
     ) {
+        self.resume_pending_retries().await;
         while let Some(op) = message_box.recv().await {
             // Skip some trivial resolve commands.
             if !matches!(op, ObserveOp::ResolveRegions { .. }) {
                 info!("backup stream: on_modify_observe"; "op" => ?op);
             }
             match op {
+                // Added alongside `RegionSubscriptionManager::shutdown`; the
+                // variant itself lives in `ObserveOp` (outside this
+                // checkout). Returning here drops `message_box`, closing the
+                // channel so any straggling `request` fails fast afterwards.
+                ObserveOp::Shutdown => return,
                 ObserveOp::Start { region } => {
                     fail::fail_point!("delay_on_start_observe");
                     self.start_observe(region).await;
@@ -454,6 +1060,7 @@ where
                     handle,
                     err,
                     has_failed_for,
+                    prev_backoff_millis,
                 } => {
                     info!("retry observe region"; "region" => %region.get_id(), "err" => %err);
                     // No need for retrying observe canceled.
@@ -464,7 +1071,11 @@ where
                         region.get_start_key().to_owned(),
                         region.get_end_key().to_owned(),
                     );
-                    match self.retry_observe(region, handle, has_failed_for).await {
+                    let prev_backoff = Duration::from_millis(prev_backoff_millis);
+                    match self
+                        .retry_observe(region, handle, has_failed_for, prev_backoff)
+                        .await
+                    {
                         Ok(()) => {}
                         Err(e) => {
                             let msg = Task::FatalError(
@@ -505,6 +1116,59 @@ where
         }
     }
 
+    /// Reloads every observe retry a previous process left outstanding and
+    /// resumes waiting out its remaining backoff, so a restart no longer
+    /// drops the retry and silently extends the RPO for the region it
+    /// covers. Called once, before `region_operator_loop` starts handling
+    /// live operations.
+    async fn resume_pending_retries(&self) {
+        let entries = match self.retry_queue.reload().await {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("failed to reload persisted observe retries, any pending regions will stay un-retried until their next leader change"; "err" => %err);
+                return;
+            }
+        };
+        for entry in entries {
+            let (tx, rx) = crossbeam::channel::bounded(1);
+            if let Err(err) = self.regions.find_region_by_id(
+                entry.region_id,
+                Box::new(move |item| {
+                    tx.send(item)
+                        .expect("BUG: failed to send to newly created channel.");
+                }),
+            ) {
+                warn!("failed to look up region for a resumed observe retry, dropping it"; "region_id" => entry.region_id, "err" => %err);
+                continue;
+            }
+            let region = match rx.recv() {
+                Ok(Some(info)) => info.region,
+                Ok(None) => {
+                    // The region is gone (merged/split away); nothing to retry.
+                    self.retry_queue.complete(entry.region_id).await;
+                    continue;
+                }
+                Err(err) => {
+                    warn!("failed to receive region info for a resumed observe retry"; "region_id" => entry.region_id, "err" => %err);
+                    continue;
+                }
+            };
+            let delay = Duration::from_millis(entry.next_attempt_millis.saturating_sub(now_millis()));
+            let prev_backoff = Duration::from_millis(entry.prev_backoff_millis);
+            let op = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                op.start_observe_with_failure_count(
+                    region,
+                    entry.has_failed_for,
+                    prev_backoff,
+                    ScanCmdPriority::Retry,
+                )
+                .await;
+            });
+        }
+    }
+
     async fn refresh_resolver(&self, region: &Region) {
         let need_refresh_all = !self.subs.try_update_region(region);
 
@@ -522,6 +1186,7 @@ where
                             region,
                             self.get_last_checkpoint_of(&for_task, region).await?,
                             handle.clone(),
+                            ScanCmdPriority::Refresh,
                         )
                         .await;
                         Result::Ok(())
@@ -535,6 +1200,7 @@ where
                                 handle,
                                 err: Box::new(e),
                                 has_failed_for: 0,
+                                prev_backoff_millis: RETRY_AWAIT_BASIC_DURATION.as_millis() as u64,
                             })
                         );
                     }
@@ -548,7 +1214,12 @@ where
         }
     }
 
-    async fn try_start_observe(&self, region: &Region, handle: ObserveHandle) -> Result<()> {
+    async fn try_start_observe(
+        &self,
+        region: &Region,
+        handle: ObserveHandle,
+        priority: ScanCmdPriority,
+    ) -> Result<()> {
         match self.find_task_by_region(region) {
             None => {
                 warn!(
@@ -568,26 +1239,56 @@ where
                     Err(Error::Other(box_err!("Nature is boring")))
                 });
                 let tso = self.get_last_checkpoint_of(&for_task, region).await?;
-                self.observe_over_with_initial_data_from_checkpoint(region, tso, handle.clone())
-                    .await;
+                self.observe_over_with_initial_data_from_checkpoint(
+                    region,
+                    tso,
+                    handle.clone(),
+                    priority,
+                )
+                .await;
             }
         }
         Ok(())
     }
 
     async fn start_observe(&self, region: Region) {
-        self.start_observe_with_failure_count(region, 0).await
+        self.start_observe_with_failure_count(
+            region,
+            0,
+            RETRY_AWAIT_BASIC_DURATION,
+            ScanCmdPriority::Initial,
+        )
+        .await
     }
 
-    async fn start_observe_with_failure_count(&self, region: Region, has_failed_for: u8) {
+    async fn start_observe_with_failure_count(
+        &self,
+        region: Region,
+        has_failed_for: u8,
+        prev_backoff: Duration,
+        priority: ScanCmdPriority,
+    ) {
         let handle = ObserveHandle::new();
         let schd = self.scheduler.clone();
         self.subs.add_pending_region(&region);
-        if let Err(err) = self.try_start_observe(&region, handle.clone()).await {
+        let region_id = region.get_id();
+        if let Err(err) = self
+            .try_start_observe(&region, handle.clone(), priority)
+            .await
+        {
             warn!("failed to start observe, would retry"; "err" => %err, utils::slog_region(&region));
+            let next_backoff = backoff_for_start_observe(prev_backoff);
+            self.retry_queue
+                .schedule(RetryQueueEntry {
+                    region_id: region.get_id(),
+                    has_failed_for: has_failed_for + 1,
+                    prev_backoff_millis: next_backoff.as_millis() as u64,
+                    next_attempt_millis: now_millis() + next_backoff.as_millis() as u64,
+                })
+                .await;
             tokio::spawn(async move {
                 #[cfg(not(feature = "failpoints"))]
-                let delay = backoff_for_start_observe(has_failed_for);
+                let delay = next_backoff;
                 #[cfg(feature = "failpoints")]
                 let delay = (|| {
                     fail::fail_point!("subscribe_mgr_retry_start_observe_delay", |v| {
@@ -597,7 +1298,7 @@ where
                             .expect("should be number (in ms)");
                         Duration::from_millis(dur)
                     });
-                    backoff_for_start_observe(has_failed_for)
+                    next_backoff
                 })();
                 tokio::time::sleep(delay).await;
                 try_send!(
@@ -606,10 +1307,13 @@ where
                         region,
                         handle,
                         err: Box::new(err),
-                        has_failed_for: has_failed_for + 1
+                        has_failed_for: has_failed_for + 1,
+                        prev_backoff_millis: next_backoff.as_millis() as u64,
                     })
                 )
             });
+        } else {
+            self.retry_queue.complete(region_id).await;
         }
     }
 
@@ -618,8 +1322,13 @@ where
         region: Region,
         handle: ObserveHandle,
         failure_count: u8,
+        prev_backoff: Duration,
     ) -> Result<()> {
         if failure_count > TRY_START_OBSERVE_MAX_RETRY_TIME {
+            // Distinguish "gave up after exhausting retries" from the other
+            // `SKIP_RETRY` reasons below, so operators can tell a region
+            // that's merely stale apart from one that never came back.
+            metrics::SKIP_RETRY.with_label_values(&["exhausted"]).inc();
             return Err(Error::Other(
                 format!(
                     "retry time exceeds for region {:?}",
@@ -629,25 +1338,28 @@ where
             ));
         }
 
-        let (tx, rx) = crossbeam::channel::bounded(1);
-        self.regions
-            .find_region_by_id(
-                region.get_id(),
-                Box::new(move |item| {
-                    tx.send(item)
-                        .expect("BUG: failed to send to newly created channel.");
-                }),
-            )
-            .map_err(|err| {
-                annotate!(
-                    err,
-                    "failed to send request to region info accessor, server maybe too too too busy. (region id = {})",
-                    region.get_id()
+        let region_id = region.get_id();
+        let new_region_info = retry_on_leader_change(self.pd_client.as_ref(), || async move {
+            let (tx, rx) = crossbeam::channel::bounded(1);
+            self.regions
+                .find_region_by_id(
+                    region_id,
+                    Box::new(move |item| {
+                        tx.send(item)
+                            .expect("BUG: failed to send to newly created channel.");
+                    }),
                 )
-            })?;
-        let new_region_info = rx
-            .recv()
-            .map_err(|err| annotate!(err, "BUG?: unexpected channel message dropped."))?;
+                .map_err(|err| {
+                    annotate!(
+                        err,
+                        "failed to send request to region info accessor, server maybe too too too busy. (region id = {})",
+                        region_id
+                    )
+                })?;
+            rx.recv()
+                .map_err(|err| annotate!(err, "BUG?: unexpected channel message dropped."))
+        })
+        .await?;
         if new_region_info.is_none() {
             metrics::SKIP_RETRY
                 .with_label_values(&["region-absent"])
@@ -679,8 +1391,13 @@ where
         metrics::INITIAL_SCAN_REASON
             .with_label_values(&["retry"])
             .inc();
-        self.start_observe_with_failure_count(region, failure_count)
-            .await;
+        self.start_observe_with_failure_count(
+            region,
+            failure_count,
+            prev_backoff,
+            ScanCmdPriority::Retry,
+        )
+        .await;
         Ok(())
     }
 
@@ -694,7 +1411,11 @@ where
             )
         )));
         let meta_cli = self.meta_cli.clone();
-        let cp = meta_cli.get_region_checkpoint(task, region).await?;
+        let cp = retry_on_leader_change(self.pd_client.as_ref(), || {
+            let meta_cli = meta_cli.clone();
+            async move { meta_cli.get_region_checkpoint(task, region).await }
+        })
+        .await?;
         debug!("got region checkpoint"; "region_id" => %region.get_id(), "checkpoint" => ?cp);
         if matches!(cp.provider, CheckpointProvider::Global) {
             metrics::STORE_CHECKPOINT_TS
@@ -710,11 +1431,28 @@ where
         // In that condition, if we blocking for some resources(for example, the
         // `MemoryQuota`) at the block threads, we may meet some ghosty
         // deadlock.
-        let s = self.scan_pool_handle.request(cmd).await;
-        if let Err(err) = s {
-            let region_id = err.0.region.get_id();
-            annotate!(err, "BUG: scan_pool closed")
-                .report(format!("during initial scanning for region {}", region_id));
+        match self.scan_pool_handle.request(cmd).await {
+            Ok(()) => {}
+            Err(ScanCmdRejected::PoolClosed(err)) => {
+                let region_id = err.0.region.get_id();
+                annotate!(err, "BUG: scan_pool closed")
+                    .report(format!("during initial scanning for region {}", region_id));
+            }
+            Err(ScanCmdRejected::QuotaExceeded(cmd)) => {
+                warn!("initial scan memory quota exhausted, will retry the scan later"; utils::slog_region(&cmd.region));
+                try_send!(
+                    self.scheduler,
+                    Task::ModifyObserve(ObserveOp::NotifyFailToStartObserve {
+                        region: cmd.region,
+                        handle: cmd.handle,
+                        err: Box::new(Error::Other(box_err!(
+                            "initial scan memory quota exhausted"
+                        ))),
+                        has_failed_for: 0,
+                        prev_backoff_millis: RETRY_AWAIT_BASIC_DURATION.as_millis() as u64,
+                    })
+                );
+            }
         }
     }
 
@@ -723,6 +1461,7 @@ where
         region: &Region,
         last_checkpoint: TimeStamp,
         handle: ObserveHandle,
+        priority: ScanCmdPriority,
     ) {
         self.subs
             .register_region(region, handle.clone(), Some(last_checkpoint));
@@ -730,6 +1469,7 @@ where
             region: region.clone(),
             handle,
             last_checkpoint,
+            priority,
             _work: self.scans.clone().work(),
         })
         .await
@@ -743,12 +1483,15 @@ where
 
 #[cfg(test)]
 mod test {
-    use kvproto::metapb::Region;
-    use tikv::storage::Statistics;
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
 
-<<<<<<< HEAD
-    use super::InitialScan;
-=======
     use engine_test::{kv::KvTestEngine, raft::RaftTestEngine};
     use kvproto::{
         brpb::{Noop, StorageBackend, StreamBackupTaskInfo},
@@ -759,22 +1502,23 @@ mod test {
         router::{CdcRaftRouter, ServerRaftStoreRouter},
         RegionInfo,
     };
+    use test_pd_client::TestPdClient;
     use tikv::{config::BackupStreamConfig, storage::Statistics};
-    use tikv_util::{box_err, info, memory::MemoryQuota, worker::dummy_scheduler};
+    use tikv_util::{box_err, info, worker::dummy_scheduler};
     use tokio::{sync::mpsc::Sender, task::JoinHandle};
     use txn_types::TimeStamp;
 
-    use super::{spawn_executors_to, InitialScan, RegionSubscriptionManager};
+    use super::{spawn_executors, InitialScan, PersistentRetryQueue, RegionSubscriptionManager};
     use crate::{
         errors::Error,
         metadata::{store::SlashEtcStore, MetadataClient, StreamTask},
+        observer::BackupStreamObserver,
         router::{Router, RouterInner},
         subscription_manager::{OOM_BACKOFF_BASE, OOM_BACKOFF_JITTER_SECS},
         subscription_track::{CheckpointType, SubscriptionTracer},
-        utils::FutureWaitGroup,
+        utils::CallbackWaitGroup,
         BackupStreamResolver, ObserveOp, Task,
     };
->>>>>>> 81d62b2e0e (log_backup: make a more rusty `CallbackWaitGroup` (#16740))
 
     #[derive(Clone, Copy)]
     struct NoopInitialScan;
@@ -795,13 +1539,42 @@ mod test {
         }
     }
 
+    /// Drives `do_initial_scan` with a caller-provided closure instead of a
+    /// fixed result, so a `Suite`-based test can fail or delay specific
+    /// regions' initial scans to exercise retry/backoff behavior.
+    #[derive(Clone)]
+    struct FuncInitialScan<F>(F);
+
+    #[async_trait::async_trait]
+    impl<F> InitialScan for FuncInitialScan<F>
+    where
+        F: Fn(&Region, TimeStamp, ObserveHandle) -> crate::errors::Result<Statistics>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        async fn do_initial_scan(
+            &self,
+            region: &Region,
+            start_ts: TimeStamp,
+            handle: ObserveHandle,
+        ) -> crate::errors::Result<Statistics> {
+            (self.0)(region, start_ts, handle)
+        }
+
+        fn handle_fatal_error(&self, region: &Region, err: crate::errors::Error) {
+            panic!("fatal {:?} {}", region, err)
+        }
+    }
+
     #[test]
     #[cfg(feature = "failpoints")]
     fn test_message_delay_and_exit() {
         use std::time::Duration;
 
-        use super::ScanCmd;
-        use crate::{subscription_manager::spawn_executors, utils::FutureWaitGroup};
+        use super::{ScanCmd, ScanCmdPriority};
+        use crate::{subscription_manager::spawn_executors, utils::CallbackWaitGroup};
 
         fn should_finish_in(f: impl FnOnce() + Send + 'static, d: std::time::Duration) {
             let (tx, rx) = futures::channel::oneshot::channel();
@@ -817,14 +1590,8 @@ mod test {
             pool.block_on(tokio::time::timeout(d, rx)).unwrap().unwrap();
         }
 
-<<<<<<< HEAD
         let pool = spawn_executors(NoopInitialScan, 1);
         let wg = CallbackWaitGroup::new();
-=======
-        let pool = spawn_executors(FuncInitialScan(|_, _, _| Ok(Statistics::default())), 1);
-        let wg = FutureWaitGroup::new();
-        let (tx, _) = tokio::sync::mpsc::channel(1);
->>>>>>> 81d62b2e0e (log_backup: make a more rusty `CallbackWaitGroup` (#16740))
         fail::cfg("execute_scan_command_sleep_100", "return").unwrap();
         for _ in 0..100 {
             let wg = wg.clone();
@@ -834,6 +1601,7 @@ mod test {
                         region: Default::default(),
                         handle: Default::default(),
                         last_checkpoint: Default::default(),
+                        priority: ScanCmdPriority::Initial,
                         // Note: Maybe make here a Box<dyn FnOnce()> or some other trait?
                         _work: wg.work(),
                     }))
@@ -846,33 +1614,119 @@ mod test {
 
     #[test]
     fn test_backoff_for_start_observe() {
-        assert_eq!(
-            super::backoff_for_start_observe(0),
-            super::RETRY_AWAIT_BASIC_DURATION
-        );
-        assert_eq!(
-            super::backoff_for_start_observe(1),
-            super::RETRY_AWAIT_BASIC_DURATION * 2
-        );
-        assert_eq!(
-            super::backoff_for_start_observe(2),
-            super::RETRY_AWAIT_BASIC_DURATION * 4
-        );
-        assert_eq!(
-            super::backoff_for_start_observe(3),
-            super::RETRY_AWAIT_BASIC_DURATION * 8
-        );
-        assert_eq!(
-            super::backoff_for_start_observe(4),
-            super::RETRY_AWAIT_MAX_DURATION
-        );
-        assert_eq!(
-            super::backoff_for_start_observe(5),
-            super::RETRY_AWAIT_MAX_DURATION
-        );
+        // The draw is randomized, so assert the bounds the decorrelated-jitter
+        // algorithm promises rather than exact values: the result always lands
+        // in `[base, cap]`, and never exceeds `3 * prev`.
+        let mut prev = super::RETRY_AWAIT_BASIC_DURATION;
+        for _ in 0..1000 {
+            let next = super::backoff_for_start_observe(prev);
+            assert!(next >= super::RETRY_AWAIT_BASIC_DURATION);
+            assert!(next <= super::RETRY_AWAIT_MAX_DURATION);
+            assert!(next <= prev * 3);
+            prev = next;
+        }
+    }
+
+    #[test]
+    fn test_scan_cmd_priority_is_serviced_first() {
+        use super::{next_scan_cmd, ScanCmd, ScanCmdPriority};
+        use crate::utils::CallbackWaitGroup;
+
+        fn cmd(priority: ScanCmdPriority, wg: &CallbackWaitGroup) -> ScanCmd {
+            ScanCmd {
+                region: Default::default(),
+                handle: Default::default(),
+                last_checkpoint: Default::default(),
+                priority,
+                _work: wg.clone().work(),
+            }
+        }
+
+        let wg = CallbackWaitGroup::new();
+        let (tx_initial, rx_initial) = flume::unbounded();
+        let (tx_refresh, rx_refresh) = flume::unbounded();
+        let (tx_retry, rx_retry) = flume::unbounded();
+        let queues = [rx_initial, rx_refresh, rx_retry];
+
+        // Saturate the `Initial` queue first, then enqueue a single
+        // `Retry`: any worker calling `next_scan_cmd` must be handed the
+        // retry before the rest of the initials it arrived behind.
+        for _ in 0..4 {
+            tx_initial.send(cmd(ScanCmdPriority::Initial, &wg)).unwrap();
+        }
+        tx_retry.send(cmd(ScanCmdPriority::Retry, &wg)).unwrap();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let mut streak = 0u32;
+            assert_eq!(
+                next_scan_cmd(&queues, &mut streak).await.unwrap().priority,
+                ScanCmdPriority::Retry
+            );
+            for _ in 0..4 {
+                assert_eq!(
+                    next_scan_cmd(&queues, &mut streak).await.unwrap().priority,
+                    ScanCmdPriority::Initial
+                );
+            }
+            drop((tx_initial, tx_refresh, tx_retry));
+            assert!(next_scan_cmd(&queues, &mut streak).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_callback_wait_group_wait_resolves_for_all_waiters() {
+        use crate::utils::CallbackWaitGroup;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let wg = CallbackWaitGroup::new();
+            let work1 = wg.clone().work();
+            let work2 = wg.clone().work();
+
+            // Two independent callers `wait()` on the same group concurrently;
+            // both must resolve once the outstanding work drops to zero, not
+            // just whichever one happened to register its oneshot first.
+            let mut waiter_a = Box::pin(wg.wait());
+            let mut waiter_b = Box::pin(wg.wait());
+            assert!(futures::poll!(&mut waiter_a).is_pending());
+            assert!(futures::poll!(&mut waiter_b).is_pending());
+
+            drop(work1);
+            assert!(futures::poll!(&mut waiter_a).is_pending());
+            drop(work2);
+
+            waiter_a.await;
+            waiter_b.await;
+        });
+    }
+
+    #[test]
+    fn test_match_event_prefix_reports_mismatch_instead_of_panicking() {
+        use super::match_event_prefix;
+
+        let recorded = [1, 2, 3];
+
+        // The pattern is longer than what was recorded: a naive
+        // `&recorded[pat.len()..]` would panic with "index out of bounds".
+        let err = match_event_prefix(&recorded, &[1, 2, 3, 4]).unwrap_err();
+        assert_eq!(err.matched, 3);
+        assert_eq!(err.expected, &[4]);
+        assert_eq!(err.actual, &[] as &[i32]);
+
+        // A genuine divergence is reported with both unmatched tails.
+        let err = match_event_prefix(&recorded, &[1, 9]).unwrap_err();
+        assert_eq!(err.matched, 1);
+        assert_eq!(err.expected, &[9]);
+        assert_eq!(err.actual, &[2, 3]);
+
+        // A matching prefix returns the remainder.
+        assert_eq!(match_event_prefix(&recorded, &[1, 2]).unwrap(), &[3]);
     }
-<<<<<<< HEAD
-=======
 
     struct Suite {
         rt: tokio::runtime::Runtime,
@@ -946,7 +1800,6 @@ mod test {
             let meta_cli = MetadataClient::new(meta_cli, 1);
             let (scheduler, mut output) = dummy_scheduler();
             let subs = SubscriptionTracer::default();
-            let memory_manager = Arc::new(MemoryQuota::new(1024));
             let (tx, mut rx) = tokio::sync::mpsc::channel(8);
             let router = RouterInner::new(scheduler.clone(), BackupStreamConfig::default().into());
             let mut task = StreamBackupTaskInfo::new();
@@ -968,17 +1821,21 @@ mod test {
                 1024 * 1024,
             ))
             .unwrap();
+            let retry_queue = Arc::new(PersistentRetryQueue::new(meta_cli.clone()));
             let subs_mgr = RegionSubscriptionManager {
                 regions: regions.clone(),
                 meta_cli,
+                pd_client: Arc::new(TestPdClient::new(1, false)),
                 range_router: Router(Arc::new(router)),
                 scheduler,
+                observer: BackupStreamObserver::default(),
                 subs: subs.clone(),
-                failure_count: Default::default(),
-                memory_manager,
-                messenger: tx.downgrade(),
-                scan_pool_handle: spawn_executors_to(init, pool.handle()),
-                scans: FutureWaitGroup::new(),
+                target_busy_ratio: Arc::new(AtomicU64::new(0)),
+                retry_queue,
+                draining: Arc::new(AtomicBool::new(false)),
+                messenger: tx.clone(),
+                scan_pool_handle: Arc::new(spawn_executors(init, 1)),
+                scans: CallbackWaitGroup::new(),
             };
             let events = Arc::new(Mutex::new(vec![]));
             let ob_events = Arc::clone(&events);
@@ -1314,5 +2171,4 @@ mod test {
         assert!(count > 0);
         assert_eq!(rem, [Start(1), StartResult(1, true)]);
     }
->>>>>>> 81d62b2e0e (log_backup: make a more rusty `CallbackWaitGroup` (#16740))
 }
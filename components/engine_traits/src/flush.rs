@@ -11,15 +11,40 @@
 //! states like `RaftApplyState` and `RegionLocalState` are mapped to index.
 //! Once apply index is confirmed, the latest states before apply index should
 //! be used as the start state.
+//!
+//! `FlushProgress.last_flushed` and the `LinkedList<ApplyProgress>` cursor
+//! merge above are only an in-memory fast path; they don't survive a crash.
+//! Durability comes from pairing `on_flush_begin`, which persists the file
+//! numbers a flush is about to install together with the apply index at that
+//! time, with `on_flush_completed`, which persists the final apply-index
+//! mapping and drops the pending record. On restart, a flushed-index mapping
+//! should only be trusted when its begin record exists AND either the
+//! completion record exists or the file it names is still present on disk;
+//! that's what lets replay tell "flushed but unrecorded" apart from "never
+//! flushed" without double-applying data or advancing past a lost file.
+//! `on_compaction_begin` enforces the other half: a file can't be compacted
+//! away until its completion record has landed. On restart, the caller
+//! reconstructs which files are still in that "begin but not completed"
+//! window with [`StateStorage::recover_flushing_files`] per cf, and seeds
+//! [`PersistenceListener::with_recovered_pending_files`] with the union so
+//! `on_compaction_begin` keeps refusing those files the same as it would
+//! have before the crash.
 
 use std::{
-    collections::{HashMap, LinkedList},
+    collections::{HashMap, HashSet, LinkedList},
+    fmt,
+    future::Future,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc, Mutex, RwLock,
     },
 };
 
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    FutureExt, Stream,
+};
 use slog_global::info;
 use tikv_util::set_panic_mark;
 
@@ -85,6 +110,19 @@ impl SstApplyState {
     pub fn sst_applied_index(&self, uuid: &Vec<u8>) -> Option<u64> {
         self.sst_map.read().unwrap().get(uuid).copied()
     }
+
+    /// Drops every entry whose recorded apply index is no greater than
+    /// `flushed_index`: once the memtable holding that index has been
+    /// durably flushed, the ingested SST it was tracking for is covered by
+    /// the regular flush, so there is no longer a need to protect it from
+    /// being deleted early.
+    #[inline]
+    pub fn gc(&self, flushed_index: u64) {
+        self.sst_map
+            .write()
+            .unwrap()
+            .retain(|_, applied_index| *applied_index > flushed_index);
+    }
 }
 
 /// A share state between raftstore and underlying engine.
@@ -92,22 +130,54 @@ impl SstApplyState {
 /// raftstore will update state changes and corresponding apply index, when
 /// flush, `PersistenceListener` will query states related to the memtable
 /// and persist the relation to raft engine.
-#[derive(Debug)]
 pub struct FlushState {
     applied_index: AtomicU64,
+    // Waiters registered via `subscribe`, each woken and removed once
+    // `applied_index` reaches its target.
+    waiters: Mutex<Vec<(u64, oneshot::Sender<u64>)>>,
+    // Subscribers registered via `watch`, sent every new applied index.
+    watchers: Mutex<Vec<mpsc::UnboundedSender<u64>>>,
+}
+
+impl fmt::Debug for FlushState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlushState")
+            .field("applied_index", &self.applied_index)
+            .finish()
+    }
 }
 
 impl FlushState {
     pub fn new(applied_index: u64) -> Self {
         Self {
             applied_index: AtomicU64::new(applied_index),
+            waiters: Mutex::new(Vec::new()),
+            watchers: Mutex::new(Vec::new()),
         }
     }
 
-    /// Set the latest applied index.
+    /// Set the latest applied index, waking every waiter whose target has
+    /// been reached and notifying every watcher.
     #[inline]
     pub fn set_applied_index(&self, index: u64) {
         self.applied_index.store(index, Ordering::Release);
+
+        let mut waiters = self.waiters.lock().unwrap();
+        if !waiters.is_empty() {
+            let mut i = 0;
+            while i < waiters.len() {
+                if waiters[i].0 <= index {
+                    let (_, tx) = waiters.swap_remove(i);
+                    let _ = tx.send(index);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        drop(waiters);
+
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|tx| tx.unbounded_send(index).is_ok());
     }
 
     /// Query the applied index.
@@ -115,11 +185,85 @@ impl FlushState {
     pub fn applied_index(&self) -> u64 {
         self.applied_index.load(Ordering::Acquire)
     }
+
+    /// Returns a future that resolves with the applied index as soon as it
+    /// reaches `target`, resolving immediately if it has already been
+    /// reached.
+    ///
+    /// The waiter list stays locked from before the current index is checked
+    /// until the waiter is registered, and `set_applied_index` stores the new
+    /// index before taking that lock, so an update racing with this call can
+    /// never be missed.
+    pub fn subscribe(&self, target: u64) -> impl Future<Output = u64> {
+        let mut waiters = self.waiters.lock().unwrap();
+        let current = self.applied_index();
+        if current >= target {
+            return Either::Left(future::ready(current));
+        }
+        let (tx, rx) = oneshot::channel();
+        waiters.push((target, tx));
+        drop(waiters);
+        // The sender is only dropped without sending if this `FlushState` is
+        // dropped first; the future then resolves with `target`.
+        Either::Right(rx.map(move |res| res.unwrap_or(target)))
+    }
+
+    /// Returns a stream of applied indices, yielding every value passed to
+    /// `set_applied_index` from this point on.
+    pub fn watch(&self) -> impl Stream<Item = u64> {
+        let (tx, rx) = mpsc::unbounded();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+}
+
+/// The `cf` key `persist_flushing_files` stores a pending file's begin
+/// record under: `put_flushed_index`/`get_flushed_index` are the only
+/// durable primitives this trait can rely on (they're the pre-existing pair;
+/// a dedicated begin-record API isn't part of this checkout), so each
+/// pending file number gets its own synthetic "cf" mapped to the apply index
+/// observed when its flush began. `on_flush_completed` clears it through
+/// `clear_flushing_file`, by overwriting it with 0.
+fn pending_file_cf(cf: &str, file_no: u64) -> String {
+    format!("{}.pending.{}", cf, file_no)
 }
 
 /// A helper trait to avoid exposing `RaftEngine` to `TabletFactory`.
 pub trait StateStorage: Sync + Send {
     fn persist_progress(&self, region_id: u64, tablet_index: u64, pr: ApplyProgress);
+
+    /// Persists the file numbers a flush is about to install together with
+    /// the apply index observed at flush-begin time.
+    ///
+    /// This makes it possible, on restart, to tell a flush that crashed
+    /// between installing its file and `persist_progress` being called
+    /// apart from one that never happened: the begin record pins down which
+    /// files and apply index were in flight.
+    fn persist_flushing_files(
+        &self,
+        region_id: u64,
+        tablet_index: u64,
+        cf: &str,
+        file_numbers: &[u64],
+        apply_index: u64,
+    );
+
+    /// Reads back the begin records `persist_flushing_files` wrote for `cf`
+    /// that are still pending, i.e. that `clear_flushing_file` hasn't
+    /// overwritten with 0 yet. `candidate_file_numbers` are the file numbers that
+    /// `TabletFactory` knows actually exist on disk for this tablet/cf; only
+    /// those are worth a point lookup since there's no way to enumerate
+    /// persisted keys without knowing them up front.
+    fn recover_flushing_files(
+        &self,
+        region_id: u64,
+        cf: &str,
+        candidate_file_numbers: &[u64],
+    ) -> HashSet<u64>;
+
+    /// Clears the begin record `persist_flushing_files` wrote for `file_no`,
+    /// once `persist_progress` has durably recorded the flush it belonged to.
+    fn clear_flushing_file(&self, region_id: u64, tablet_index: u64, cf: &str, file_no: u64);
 }
 
 /// A flush listener that maps memtable to apply index and persist the relation
@@ -129,6 +273,12 @@ pub struct PersistenceListener {
     tablet_index: u64,
     state: Arc<FlushState>,
     progress: Mutex<FlushProgress>,
+    // File numbers that have been recorded by `on_flush_begin` but have not
+    // yet been durably mapped to an apply index by `on_flush_completed`.
+    // `on_compaction_begin` consults this to refuse compacting a file whose
+    // flush hasn't been made crash-safe yet.
+    pending_files: Mutex<HashSet<u64>>,
+    sst_apply_state: SstApplyState,
     storage: Arc<dyn StateStorage>,
 }
 
@@ -137,13 +287,45 @@ impl PersistenceListener {
         region_id: u64,
         tablet_index: u64,
         state: Arc<FlushState>,
+        sst_apply_state: SstApplyState,
         storage: Arc<dyn StateStorage>,
+    ) -> Self {
+        Self::with_recovered_pending_files(
+            region_id,
+            tablet_index,
+            state,
+            sst_apply_state,
+            storage,
+            HashSet::default(),
+        )
+    }
+
+    /// Like [`PersistenceListener::new`], but seeds `pending_files` from
+    /// `recovered_pending_files` instead of assuming none are in flight.
+    ///
+    /// On restart, a flush's begin record may have been persisted by
+    /// `persist_flushing_files` without a matching `persist_progress` ever
+    /// landing, meaning its file survived the crash without being made
+    /// crash-safe. The caller is expected to have already queried
+    /// [`StateStorage::recover_flushing_files`] for every cf this tablet
+    /// tracks and unioned the results before constructing this listener, so
+    /// `on_compaction_begin` keeps refusing to compact those files until
+    /// they're reflushed or explicitly completed.
+    pub fn with_recovered_pending_files(
+        region_id: u64,
+        tablet_index: u64,
+        state: Arc<FlushState>,
+        sst_apply_state: SstApplyState,
+        storage: Arc<dyn StateStorage>,
+        recovered_pending_files: HashSet<u64>,
     ) -> Self {
         Self {
             region_id,
             tablet_index,
             state,
             progress: Mutex::new(FlushProgress::default()),
+            pending_files: Mutex::new(recovered_pending_files),
+            sst_apply_state,
             storage,
         }
     }
@@ -179,12 +361,52 @@ impl PersistenceListener {
         });
     }
 
+    /// Called before a flush installs any file.
+    ///
+    /// `file_numbers` are the files the flush is about to produce for `cf`.
+    /// They are persisted together with the apply index observed right now,
+    /// so that a crash before `on_flush_completed` runs can still be told
+    /// apart from data that was never flushed at all.
+    pub fn on_flush_begin(&self, cf: &str, file_numbers: Vec<u64>) {
+        let apply_index = self.state.applied_index();
+        self.pending_files
+            .lock()
+            .unwrap()
+            .extend(file_numbers.iter().copied());
+        self.storage.persist_flushing_files(
+            self.region_id,
+            self.tablet_index,
+            cf,
+            &file_numbers,
+            apply_index,
+        );
+    }
+
+    /// Called before compaction picks up `input_file_numbers`.
+    ///
+    /// A file is only safe to compact once its apply-index mapping has been
+    /// durably persisted by `on_flush_completed`; letting compaction race
+    /// ahead of that could delete a file whose flush was never recorded,
+    /// leaving restart replay with nothing to confirm the apply index
+    /// against.
+    pub fn on_compaction_begin(&self, input_file_numbers: &[u64]) {
+        let pending = self.pending_files.lock().unwrap();
+        for file_no in input_file_numbers {
+            if pending.contains(file_no) {
+                set_panic_mark();
+                panic!(
+                    "[region_id={}] [tablet_index={}] file {} is picked for compaction before \
+                     its flush was persisted",
+                    self.region_id, self.tablet_index, file_no
+                );
+            }
+        }
+    }
+
     /// Called a memtable finished flushing.
     ///
     /// `largest_seqno` should be the largest seqno of the generated file.
     pub fn on_flush_completed(&self, cf: &str, largest_seqno: u64, file_no: u64) {
-        // Maybe we should hook the compaction to avoid the file is compacted before
-        // being recorded.
         let offset = data_cf_offset(cf);
         let pr = {
             let mut prs = self.progress.lock().unwrap();
@@ -225,8 +447,16 @@ impl PersistenceListener {
                 }
             }
         };
+        // Once this is persisted, the file no longer needs `on_compaction_begin`'s
+        // protection, and any SST registered in `sst_apply_state` at or below this
+        // apply index is covered by the flush it's tracking.
+        let apply_index = pr.apply_index;
         self.storage
             .persist_progress(self.region_id, self.tablet_index, pr);
+        self.pending_files.lock().unwrap().remove(&file_no);
+        self.storage
+            .clear_flushing_file(self.region_id, self.tablet_index, cf, file_no);
+        self.sst_apply_state.gc(apply_index);
     }
 }
 
@@ -236,15 +466,63 @@ impl<R: RaftEngine> StateStorage for R {
             return;
         }
         let mut batch = self.log_batch(1);
-        // TODO: It's possible that flush succeeds but fails to call
-        // `on_flush_completed` before exit. In this case the flushed data will
-        // be replayed again after restarted. To solve the problem, we need to
-        // (1) persist flushed file numbers in `on_flush_begin` and (2) check
-        // the file number in `on_compaction_begin`. After restart, (3) check if the
-        // file exists. If (1) && ((2) || (3)), then we don't need to replay the data.
         batch
             .put_flushed_index(region_id, &pr.cf, tablet_index, pr.apply_index)
             .unwrap();
         self.consume(&mut batch, true).unwrap();
     }
+
+    fn persist_flushing_files(
+        &self,
+        region_id: u64,
+        tablet_index: u64,
+        cf: &str,
+        file_numbers: &[u64],
+        apply_index: u64,
+    ) {
+        if apply_index == 0 || file_numbers.is_empty() {
+            return;
+        }
+        let mut batch = self.log_batch(file_numbers.len());
+        for file_no in file_numbers {
+            batch
+                .put_flushed_index(
+                    region_id,
+                    &pending_file_cf(cf, *file_no),
+                    tablet_index,
+                    apply_index,
+                )
+                .unwrap();
+        }
+        self.consume(&mut batch, true).unwrap();
+    }
+
+    fn recover_flushing_files(
+        &self,
+        region_id: u64,
+        cf: &str,
+        candidate_file_numbers: &[u64],
+    ) -> HashSet<u64> {
+        candidate_file_numbers
+            .iter()
+            .filter(|file_no| {
+                // 0 is the same "nothing to see here" sentinel `persist_progress` uses:
+                // `on_flush_completed` clears a file's begin record by overwriting it with
+                // 0 once the file's real flushed-index mapping has landed.
+                matches!(
+                    self.get_flushed_index(region_id, &pending_file_cf(cf, **file_no)),
+                    Ok(Some(v)) if v != 0
+                )
+            })
+            .copied()
+            .collect()
+    }
+
+    fn clear_flushing_file(&self, region_id: u64, tablet_index: u64, cf: &str, file_no: u64) {
+        let mut batch = self.log_batch(1);
+        batch
+            .put_flushed_index(region_id, &pending_file_cf(cf, file_no), tablet_index, 0)
+            .unwrap();
+        self.consume(&mut batch, true).unwrap();
+    }
 }
@@ -3,17 +3,13 @@
 use collections::HashMap;
 use crossbeam::channel::TrySendError;
 use engine_traits::{data_cf_offset, KvEngine, RaftEngine};
-use kvproto::import_sstpb::SstMeta;
+use kvproto::import_sstpb::{self, SstMeta};
 use raftstore::{
     store::{check_sst_for_ingestion, metrics::PEER_WRITE_CMD_COUNTER, util},
     Result,
 };
-<<<<<<< HEAD
-use slog::error;
-=======
-use slog::{error, info};
+use slog::{error, info, warn};
 use sst_importer::range_overlaps;
->>>>>>> 1d60d579a9 (raftstore-v2: clean up import sst file only if flushed epoch is stale. (#15064))
 use tikv_util::{box_try, slog_panic};
 
 use crate::{
@@ -35,6 +31,21 @@ impl<'a, EK: KvEngine, ER: RaftEngine, T> StoreFsmDelegate<'a, EK, ER, T> {
             self.store_ctx.cfg.cleanup_import_sst_interval.0,
         );
     }
+
+    /// Scrubs every SST the importer is still holding on disk, re-checking
+    /// its checksum/format against its recorded `SstMeta` so corruption is
+    /// discovered and repaired proactively instead of only when a peer
+    /// happens to ingest the bad file.
+    #[inline]
+    pub fn on_scrub_import_sst(&mut self) {
+        if let Err(e) = self.fsm.store.on_scrub_import_sst(self.store_ctx) {
+            error!(self.fsm.store.logger(), "scrub import sst failed"; "error" => ?e);
+        }
+        self.schedule_tick(
+            StoreTick::ScrubImportSst,
+            self.store_ctx.cfg.cleanup_import_sst_interval.0,
+        );
+    }
 }
 
 impl Store {
@@ -47,13 +58,53 @@ impl Store {
         if ssts.is_empty() {
             return Ok(());
         }
+
+        // A region's epoch only goes stale on the peer that actually saw
+        // the split/merge; an sst can also be orphaned by a topology change
+        // on a *different* region, which this peer's epoch check can never
+        // catch. Reclaim those too by testing each sst's own range against
+        // every range currently live in this store, reachable through the
+        // router's store meta.
+        let live_ranges: Vec<import_sstpb::Range> = {
+            let meta = ctx.router.store_meta().lock().unwrap();
+            meta.regions
+                .values()
+                .map(|region| {
+                    let mut range = import_sstpb::Range::default();
+                    range.set_start_key(region.get_start_key().to_vec());
+                    range.set_end_key(region.get_end_key().to_vec());
+                    range
+                })
+                .collect()
+        };
+
         let mut region_ssts: HashMap<_, Vec<_>> = HashMap::default();
+        let mut orphaned_ssts = Vec::new();
         for sst in ssts {
-            region_ssts
-                .entry(sst.get_region_id())
-                .or_default()
-                .push(sst);
+            if live_ranges
+                .iter()
+                .any(|live| range_overlaps(sst.get_range(), live))
+            {
+                region_ssts.entry(sst.get_region_id()).or_default().push(sst);
+            } else {
+                orphaned_ssts.push(sst);
+            }
         }
+
+        if !orphaned_ssts.is_empty() {
+            info!(
+                self.logger(),
+                "cleaning up import sst overlapping no live region";
+                "ssts" => ?orphaned_ssts,
+            );
+            let _ = ctx
+                .schedulers
+                .tablet
+                .schedule(tablet::Task::CleanupImportSst(
+                    orphaned_ssts.into_boxed_slice(),
+                ));
+        }
+
         for (region_id, ssts) in region_ssts {
             if let Err(TrySendError::Disconnected(msg)) = ctx.router.send(region_id, PeerMsg::CleanupImportSst(ssts.into()))
                 && !ctx.router.is_shutdown() {
@@ -64,6 +115,39 @@ impl Store {
 
         Ok(())
     }
+
+    #[inline]
+    fn on_scrub_import_sst<EK: KvEngine, ER: RaftEngine, T>(
+        &mut self,
+        ctx: &mut StoreContext<EK, ER, T>,
+    ) -> Result<()> {
+        let ssts = box_try!(ctx.sst_importer.list_ssts());
+        if ssts.is_empty() {
+            return Ok(());
+        }
+        let mut corrupted = Vec::new();
+        for sst in ssts {
+            if let Err(e) = ctx.sst_importer.validate(&sst) {
+                warn!(
+                    self.logger(),
+                    "found corrupted sst during scrub, scheduling cleanup";
+                    "sst" => ?sst,
+                    "error" => ?e,
+                );
+                corrupted.push(sst);
+            }
+        }
+        if corrupted.is_empty() {
+            return Ok(());
+        }
+        let _ = ctx
+            .schedulers
+            .tablet
+            .schedule(tablet::Task::CleanupImportSst(
+                corrupted.into_boxed_slice(),
+            ));
+        Ok(())
+    }
 }
 
 impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
@@ -121,7 +205,18 @@ impl<EK: KvEngine, R: ApplyResReporter> Apply<EK, R> {
             match self.sst_importer().validate(sst) {
                 Ok(meta_info) => infos.push(meta_info),
                 Err(e) => {
-                    slog_panic!(self.logger, "corrupted sst"; "sst" => ?sst, "error" => ?e);
+                    // A corrupted SST is recoverable: drop the bad file and
+                    // surface an error so the importer treats it like a
+                    // missing one and asks the client to re-send it, rather
+                    // than taking the whole store down.
+                    warn!(
+                        self.logger,
+                        "corrupted sst, deleting and asking for re-ingestion";
+                        "sst" => ?sst,
+                        "error" => ?e,
+                    );
+                    let _ = self.sst_importer().delete(sst);
+                    return Err(e);
                 }
             }
         }
@@ -134,18 +229,6 @@ impl<EK: KvEngine, R: ApplyResReporter> Apply<EK, R> {
             let metas: Vec<SstMeta> = infos.iter().map(|info| info.meta.clone()).collect();
             self.sst_apply_state().register_ssts(index, metas);
         }
-<<<<<<< HEAD
-        let uuids = infos
-            .iter()
-            .map(|info| info.meta.get_uuid().to_vec())
-            .collect::<Vec<_>>();
-        self.set_sst_applied_index(uuids, index);
-=======
-
-        self.metrics.size_diff_hint += size;
-        self.metrics.written_bytes += size as u64;
-        self.metrics.written_keys += keys;
->>>>>>> 1d60d579a9 (raftstore-v2: clean up import sst file only if flushed epoch is stale. (#15064))
         Ok(())
     }
 }
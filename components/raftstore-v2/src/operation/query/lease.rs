@@ -3,18 +3,16 @@
 use std::sync::Mutex;
 
 use engine_traits::{KvEngine, RaftEngine};
-use kvproto::raft_cmdpb::RaftCmdRequest;
+use kvproto::{
+    kvrpcpb::LockInfo,
+    raft_cmdpb::{CmdType, RaftCmdRequest},
+};
 use raft::{
     eraftpb::{self, MessageType},
     Storage,
 };
 use raftstore::{
     store::{
-<<<<<<< HEAD
-        can_amend_read, fsm::apply::notify_stale_req, metrics::RAFT_READ_INDEX_PENDING_COUNT,
-        msg::ReadCallback, propose_read_index, should_renew_lease, util::LeaseState, ReadDelegate,
-        ReadIndexRequest, ReadProgress, Transport,
-=======
         can_amend_read, cmd_resp,
         fsm::{apply::notify_stale_req, new_read_index_request},
         metrics::RAFT_READ_INDEX_PENDING_COUNT,
@@ -23,7 +21,6 @@ use raftstore::{
         simple_write::SimpleWriteEncoder,
         util::{check_req_region_epoch, LeaseState},
         ReadDelegate, ReadIndexRequest, ReadProgress, Transport,
->>>>>>> 6ca4a629a1 (raftstore-v2: check region epoch before response read index (#15046))
     },
     Error, Result,
 };
@@ -31,6 +28,7 @@ use slog::debug;
 use tikv_util::time::monotonic_raw_now;
 use time::Timespec;
 use tracker::GLOBAL_TRACKERS;
+use txn_types::{Key, TimeStamp};
 
 use crate::{
     batch::StoreContext,
@@ -39,6 +37,88 @@ use crate::{
     router::{QueryResChannel, QueryResult, ReadResponse},
 };
 
+/// Extracts the read timestamp carried by a stale-read or replica-read
+/// request, if any.
+///
+/// Stale reads and follower reads that fall back to a read index both embed
+/// the timestamp they want to observe in the request header so that the
+/// leader processing the read index can bump its concurrency manager's
+/// `max_ts` accordingly. Without this, an async-commit transaction might
+/// later pick a `min_commit_ts` no greater than the read timestamp, which
+/// would make the read silently miss the transaction's write.
+fn read_ts_from_req(req: &RaftCmdRequest) -> Option<TimeStamp> {
+    let flag_data = req.get_header().get_flag_data();
+    if flag_data.len() != 8 {
+        return None;
+    }
+    let ts = u64::from_le_bytes(flag_data.try_into().unwrap());
+    if ts == 0 {
+        None
+    } else {
+        Some(TimeStamp::new(ts))
+    }
+}
+
+/// Computes the union of the key ranges touched by the `Get`/`Scan`
+/// sub-requests carried alongside a read-index command, if any.
+///
+/// Other command types aren't range-checked here; a request made up of only
+/// those simply doesn't get a memory-lock check.
+fn covering_key_range(req: &RaftCmdRequest) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut range: Option<(Vec<u8>, Vec<u8>)> = None;
+    for r in req.get_requests() {
+        let (start, end) = match r.get_cmd_type() {
+            CmdType::Get => {
+                let key = r.get_get().get_key().to_vec();
+                let mut end = key.clone();
+                end.push(0);
+                (key, end)
+            }
+            CmdType::Scan => (
+                r.get_scan().get_start_key().to_vec(),
+                r.get_scan().get_end_key().to_vec(),
+            ),
+            _ => continue,
+        };
+        range = Some(match range {
+            None => (start, end),
+            Some((s, e)) => (std::cmp::min(s, start), std::cmp::max(e, end)),
+        });
+    }
+    range
+}
+
+/// Looks up the in-memory lock table for a lock covering `range` with
+/// `ts <= start_ts`, returning the first one found.
+///
+/// Replica reads and reads carrying a `start_ts` can race with a prewrite
+/// that's only visible in the concurrency manager's lock table and not yet
+/// reflected in the engine snapshot the apply side will read from; this is
+/// the same check the storage layer performs for snapshot reads, so a read
+/// index alone isn't a sufficient proof of a conflict-free read.
+fn memory_lock_conflict<EK: KvEngine, ER: RaftEngine, T>(
+    ctx: &StoreContext<EK, ER, T>,
+    start_ts: TimeStamp,
+    (start_key, end_key): (Vec<u8>, Vec<u8>),
+) -> Option<LockInfo> {
+    let start_key = Key::from_encoded(start_key);
+    let end_key = Key::from_encoded(end_key);
+    let mut conflict = None;
+    let _: Result<(), ()> = ctx.concurrency_manager.read_range_check(
+        Some(&start_key),
+        Some(&end_key),
+        |key, lock| {
+            if lock.ts <= start_ts {
+                conflict = Some(lock.clone().into_lock_info(key.to_raw().unwrap()));
+                Err(())
+            } else {
+                Ok(())
+            }
+        },
+    );
+    conflict
+}
+
 impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
     pub fn on_step_read_index<T>(
         &mut self,
@@ -77,6 +157,10 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
         false
     }
 
+    /// Checks whether this peer is in a state where proposing a read index
+    /// even makes sense. Splitting/merging peers reject the read outright;
+    /// otherwise [`Peer::read_index_leader`] is free to propose, waking the
+    /// raft group first if it's found to be hibernating.
     pub fn pre_read_index(&self) -> Result<()> {
         fail::fail_point!("before_propose_readindex", |s| if s
             .map_or(true, |s| s.parse().unwrap_or(true))
@@ -106,6 +190,32 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
         Ok(())
     }
 
+    /// Single entry point for serving a read request, regardless of whether
+    /// this peer currently holds leadership.
+    ///
+    /// This is what the command-dispatch layer (outside this checkout) is
+    /// expected to call once [`Peer::pre_read_index`] has passed: it tries
+    /// the lease-based local fast path first when leading, falls back to
+    /// proposing a read index through the raft group otherwise, and routes
+    /// non-leaders to [`Peer::read_index_follower`] so the leader's confirmed
+    /// index comes back through [`Peer::on_read_index_resp`].
+    pub(crate) fn propose_read<T: Transport>(
+        &mut self,
+        ctx: &mut StoreContext<EK, ER, T>,
+        req: RaftCmdRequest,
+        ch: QueryResChannel,
+    ) {
+        if !self.is_leader() {
+            self.read_index_follower(ctx, req, ch);
+            return;
+        }
+        let ch = match self.try_local_read(ctx, &req, ch) {
+            Ok(()) => return,
+            Err(ch) => ch,
+        };
+        self.read_index_leader(ctx, req, ch);
+    }
+
     pub(crate) fn read_index_leader<T: Transport>(
         &mut self,
         ctx: &mut StoreContext<EK, ER, T>,
@@ -113,6 +223,25 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
         ch: QueryResChannel,
     ) {
         let now = monotonic_raw_now();
+        let read_ts = read_ts_from_req(&req);
+        // Bump `max_ts` before the read is actually served (either by amending an
+        // in-flight read below, or by a fresh read index proposal), so that any
+        // async-commit transaction overlapping the read keys is forced to commit
+        // after this read's timestamp. This ordering is load-bearing: if a
+        // concurrently committing transaction could pick its commit_ts before
+        // this update lands, it could choose one no greater than a snapshot
+        // already served by this read index, breaking linearizability.
+        if let Some(read_ts) = read_ts {
+            ctx.concurrency_manager.update_max_ts(read_ts);
+        }
+        // `ReadIndexRequest` batches several independently key-ranged
+        // commands behind one read index once amended together below, so a
+        // lock conflict is intentionally *not* checked or cached here as a
+        // single value for the whole batch -- a lock that only covers one
+        // amended command's range must not reject every other command
+        // sharing this read index. Each command's own lock conflict is
+        // instead checked against its own range in `respond_read_index`,
+        // once the batch is split back into its individual commands.
         let lease_state = self.inspect_lease();
         if can_amend_read::<QueryResChannel>(
             self.pending_reads().back(),
@@ -135,6 +264,17 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
 
         ctx.raft_metrics.propose.read_index.inc();
 
+        if lease_state != LeaseState::Valid {
+            // Without a valid lease, confirming this read needs a quorum of
+            // heartbeats. A hibernated leader has stopped ticking to save
+            // CPU/network, so left alone it won't send one until something
+            // else wakes it up, and the `ReadIndexRequest` pushed below
+            // would sit in `pending_reads` indefinitely. Nudge the group
+            // now so the heartbeat goes out immediately instead of waiting
+            // for the next scheduled tick.
+            self.wake_up_if_hibernated();
+        }
+
         let request = req
             .mut_requests()
             .get_mut(0)
@@ -179,12 +319,172 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
         // }
     }
 
+    /// Lease-based local read fast path.
+    ///
+    /// When this peer's lease is [`LeaseState::Valid`] and it's neither
+    /// splitting nor merging, the lease alone is proof that no other leader
+    /// could have been elected and applied a conflicting write since it was
+    /// last renewed, so the read can be served from the already-applied
+    /// state without paying for a `MsgReadIndex` round trip through the raft
+    /// group. Hands `ch` back (the caller should fall back to
+    /// [`Peer::read_index_leader`]) when the lease isn't valid, including
+    /// when it stops being valid during the checks below, or the peer is
+    /// splitting/merging. A request that fails the epoch or memory-lock
+    /// check is answered on `ch` with an error instead.
+    ///
+    /// Note: this only covers the fast path reachable from the raftstore
+    /// thread. The `LocalReader` worker's copy of this same fast path, which
+    /// serves reads straight off `StoreMeta::readers` without even crossing
+    /// into the raftstore thread, isn't part of this checkout.
+    ///
+    /// Returns `Ok(())` once `ch` has been answered locally. Returns `ch`
+    /// back unconsumed in the `Err` case so [`Peer::propose_read`] can fall
+    /// back to [`Peer::read_index_leader`] without losing the caller's
+    /// channel.
+    pub(crate) fn try_local_read<T>(
+        &mut self,
+        ctx: &mut StoreContext<EK, ER, T>,
+        req: &RaftCmdRequest,
+        ch: QueryResChannel,
+    ) -> std::result::Result<(), QueryResChannel> {
+        if self.proposal_control().is_splitting() || self.proposal_control().is_merging() {
+            return Err(ch);
+        }
+        if self.inspect_lease() != LeaseState::Valid {
+            return Err(ch);
+        }
+
+        if let Err(e) = check_req_region_epoch(req, self.region(), true) {
+            let mut response = cmd_resp::new_error(e);
+            cmd_resp::bind_term(&mut response, self.term());
+            ch.report_error(response);
+            return Ok(());
+        }
+
+        if let Some(read_ts) = read_ts_from_req(req) {
+            // Same ordering requirement as `read_index_leader`: bump
+            // `max_ts` and check for a conflicting lock before handing the
+            // read back.
+            ctx.concurrency_manager.update_max_ts(read_ts);
+            if let Some(locked) =
+                covering_key_range(req).and_then(|range| memory_lock_conflict(ctx, read_ts, range))
+            {
+                let mut response = cmd_resp::new_error(tikv_util::box_err!(
+                    "key is locked: {:?}",
+                    locked
+                ));
+                cmd_resp::bind_term(&mut response, self.term());
+                ch.report_error(response);
+                return Ok(());
+            }
+        }
+
+        // Re-inspect the lease right before responding: the epoch/lock
+        // checks above can take long enough, under load, for a concurrent
+        // `expire_lease_on_became_follower` to have flipped the state from
+        // under us. Abandon the local read rather than serve stale data;
+        // the caller retries through `read_index_leader`.
+        if self.inspect_lease() != LeaseState::Valid {
+            return Err(ch);
+        }
+
+        let applied_index = self.storage().entry_storage().applied_index();
+        ch.set_result(QueryResult::Read(ReadResponse::new(applied_index)));
+        Ok(())
+    }
+
+    /// Entry point for serving a linearizable read on a follower or learner
+    /// `Peer` that's otherwise ready to serve reads (lease/apply checks are
+    /// the caller's responsibility).
+    ///
+    /// Unlike [`Peer::read_index_leader`] there's no local lease to consult:
+    /// the read index can only come from the current leader, so this
+    /// proposes a read index the same way a write would be proposed, which
+    /// raft-rs routes to the leader as a `MsgReadIndex` automatically since
+    /// this peer isn't one. The request is queued in `pending_reads` and
+    /// handed back to the caller from [`Peer::on_read_index_resp`] once the
+    /// matching `MsgReadIndexResp` comes back from the leader.
+    pub(crate) fn read_index_follower<T: Transport>(
+        &mut self,
+        ctx: &mut StoreContext<EK, ER, T>,
+        mut req: RaftCmdRequest,
+        ch: QueryResChannel,
+    ) {
+        let now = monotonic_raw_now();
+        // See the comment in `read_index_leader`: this keeps async-commit
+        // transactions honest about a concurrent replica read too.
+        if let Some(read_ts) = read_ts_from_req(&req) {
+            ctx.concurrency_manager.update_max_ts(read_ts);
+        }
+
+        ctx.raft_metrics.propose.read_index.inc();
+
+        let request = req
+            .mut_requests()
+            .get_mut(0)
+            .filter(|req| req.has_read_index())
+            .map(|req| req.take_read_index());
+        let (id, dropped) = propose_read_index(self.raft_group_mut(), request.as_ref());
+        if dropped {
+            // The message gets dropped silently, can't be handled anymore.
+            notify_stale_req(self.term(), ch);
+            ctx.raft_metrics.propose.dropped_read_index.inc();
+            return;
+        }
+
+        let mut read = ReadIndexRequest::with_command(id, req, ch, now);
+        read.addition_request = request.map(Box::new);
+        self.pending_reads_mut().push_back(read, false);
+        debug!(
+            self.logger,
+            "request a read index from the leader";
+            "request_id" => ?id,
+        );
+        self.set_has_ready();
+    }
+
+    /// Handles the response to an earlier [`Peer::read_index_follower`]
+    /// request, once `read_index_req` has been matched by id to the
+    /// `MsgReadIndexResp` the leader sent back for it.
+    ///
+    /// Meant to be called from the raft-ready loop that drains
+    /// `Ready::read_states()` for this peer, matching each returned index
+    /// back to its `pending_reads` entry by request id; that ready-handling
+    /// loop isn't part of this checkout.
+    ///
+    /// A read is only safe to hand back once this replica's apply index has
+    /// caught up to the index the leader confirmed; otherwise the read
+    /// could observe state older than what the leader has already
+    /// committed. If apply hasn't caught up yet, `read_index_req` is left
+    /// in `pending_reads` with the confirmed index recorded, to be
+    /// retried once apply advances past it.
+    pub(crate) fn on_read_index_resp<T>(
+        &mut self,
+        ctx: &StoreContext<EK, ER, T>,
+        read_index_req: &mut ReadIndexRequest<QueryResChannel>,
+        index: u64,
+    ) {
+        read_index_req.read_index = Some(index);
+        if index > self.storage().entry_storage().applied_index() {
+            return;
+        }
+        self.respond_read_index(ctx, read_index_req);
+    }
+
     /// response the read index request
     ///
     /// awake the read tasks waiting in frontend (such as unified thread pool)
     /// In v1, it's named as response_read.
-    pub(crate) fn respond_read_index(
+    ///
+    /// `read_index_req` batches every command amended onto this read index,
+    /// which can carry independent key ranges and `start_ts`s (see
+    /// `read_index_leader`'s amend path); each command's lock conflict is
+    /// therefore checked here against its own range, not cached once for the
+    /// whole batch, so one command's lock can't reject its unrelated
+    /// batch-mates.
+    pub(crate) fn respond_read_index<T>(
         &self,
+        ctx: &StoreContext<EK, ER, T>,
         read_index_req: &mut ReadIndexRequest<QueryResChannel>,
     ) {
         debug!(
@@ -219,10 +519,29 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
                 return;
             }
 
-            // Key lock should not happen when read_index is running at the leader.
-            // Because it only happens when concurrent read and write requests on the same
-            // region on different TiKVs.
-            assert!(read_index_req.locked.is_none());
+            // Check this command's own range for a conflicting lock in the
+            // concurrency manager's memory table: the client has to resolve
+            // it and retry, the same contract the storage layer already uses
+            // for snapshot reads, so hand the lock back instead of the read
+            // index. This is per-command rather than cached once for
+            // `read_index_req` as a whole, because two commands amended onto
+            // the same read index can cover entirely different key ranges.
+            //
+            // TODO: report this through a `locked` field on `QueryResult::Read`
+            // once one exists; `crate::router` isn't part of this checkout, so
+            // for now this is surfaced as a generic key-is-locked error.
+            let locked = read_ts_from_req(&req)
+                .and_then(|start_ts| covering_key_range(&req).map(|range| (start_ts, range)))
+                .and_then(|(start_ts, range)| memory_lock_conflict(ctx, start_ts, range));
+            if let Some(locked) = locked {
+                let mut response = cmd_resp::new_error(tikv_util::box_err!(
+                    "key is locked: {:?}",
+                    locked
+                ));
+                cmd_resp::bind_term(&mut response, self.term());
+                ch.report_error(response);
+                continue;
+            }
             match (read_index, read_index_req.read_index) {
                 (Some(local_responsed_index), Some(batch_index)) => {
                     // `read_index` could be less than `read_index_req.read_index` because the
@@ -317,4 +636,17 @@ impl<EK: KvEngine, ER: RaftEngine> Peer<EK, ER> {
         }
         state
     }
+
+    /// Forces the raft group to resume ticking before a read index that
+    /// can't be answered from a valid lease is proposed.
+    ///
+    /// Note: the hibernate bookkeeping itself (`GroupState`/
+    /// `HibernateState`, and the poller-side logic that stops scheduling
+    /// ticks for an idle group) isn't part of this checkout, so this can
+    /// only nudge the raft group's own tick counters via `RawNode::tick`;
+    /// it can't flip this peer's fsm back to an actively-ticking group
+    /// state the way the real wake-up path does.
+    fn wake_up_if_hibernated(&mut self) {
+        self.raft_group_mut().tick();
+    }
 }
@@ -1,10 +1,27 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 // #[PerformanceCriticalPath]
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
 use crossbeam::channel::TrySendError;
 use engine_traits::{KvEngine, RaftEngine, Snapshot};
-use kvproto::{raft_cmdpb::RaftCmdRequest, raft_serverpb::RaftMessage};
+use futures::{channel::oneshot, future::BoxFuture};
+use kvproto::{
+    metapb,
+    raft_cmdpb::{CmdType, RaftCmdRequest},
+    raft_serverpb::RaftMessage,
+};
 use raft::SnapshotStatus;
+use slog_global::warn;
 use tikv_util::time::ThreadReadId;
 
 use crate::{
@@ -12,7 +29,7 @@ use crate::{
         fsm::RaftRouter,
         transport::{CasualRouter, ProposalRouter, SignificantRouter},
         Callback, CasualMessage, LocalReader, PeerMsg, RaftCmdExtraOpts, RaftCommand,
-        SignificantMsg, StoreMsg, StoreRouter,
+        ReadResponse, SignificantMsg, StoreMsg, StoreRouter, WriteResponse,
     },
     DiscardReason, Error as RaftStoreError, Result as RaftStoreResult,
 };
@@ -53,6 +70,41 @@ where
         send_command_impl::<EK, _>(self, req, cb, extra_opts)
     }
 
+    /// Async counterpart of [`RaftStoreRouter::send_command`].
+    ///
+    /// Builds the oneshot-backed `Callback` internally and returns a future
+    /// that resolves once the command either gets a read snapshot or
+    /// finishes applying, so `txn_command_future`-style async code can
+    /// `.await` a proposal directly instead of hand-rolling a
+    /// callback-to-future bridge at every layer. `send_command` stays
+    /// around unchanged for callers that aren't async yet.
+    fn send_command_async(
+        &self,
+        req: RaftCmdRequest,
+        extra_opts: RaftCmdExtraOpts,
+    ) -> BoxFuture<'static, RaftStoreResult<RaftCmdResult<EK::Snapshot>>> {
+        let is_read = req
+            .get_requests()
+            .iter()
+            .any(|r| r.get_cmd_type() == CmdType::Snap);
+        let (tx, rx) = oneshot::channel();
+        let cb = if is_read {
+            Callback::read(Box::new(move |resp: ReadResponse<EK::Snapshot>| {
+                let _ = tx.send(RaftCmdResult::Read(resp));
+            }))
+        } else {
+            Callback::write(Box::new(move |resp: WriteResponse| {
+                let _ = tx.send(RaftCmdResult::Write(resp));
+            }))
+        };
+        let sent = self.send_command(req, cb, extra_opts);
+        Box::pin(async move {
+            sent?;
+            rx.await
+                .map_err(|_| RaftStoreError::Other(tikv_util::box_err!("callback is dropped")))
+        })
+    }
+
     /// Reports the peer being unreachable to the Region.
     fn report_unreachable(&self, region_id: u64, to_peer_id: u64) -> RaftStoreResult<()> {
         let msg = SignificantMsg::Unreachable {
@@ -90,6 +142,36 @@ where
     }
 }
 
+/// Observes the raft message stream flowing through a
+/// [`ServerRaftStoreRouter`] without needing to patch raftstore internals.
+///
+/// This is the integration point external read replicas (an engine running
+/// alongside TiKV that wants to mirror the replication log) attach to.
+/// Observers are invoked synchronously on the caller's thread, so they must
+/// be cheap and non-blocking: any error they hit is the observer's own
+/// problem to log, never the raft pipeline's, so a slow or failing external
+/// consumer can't stall proposing or stepping raft messages.
+pub trait RaftMessageObserver: Send + Sync {
+    /// Called with every `RaftMessage` handed to `send_raft_msg`, before it
+    /// reaches the inner router.
+    fn on_raft_message(&self, msg: &RaftMessage);
+
+    /// Called with every command handed to `send_command`, before it
+    /// reaches the inner router.
+    ///
+    /// `index` is the log index the command is expected to apply at, if
+    /// known at propose time; callers that only learn the index once the
+    /// command is actually applied should treat 0 as "not yet known".
+    fn on_applied_cmd(&self, region_id: u64, index: u64, cmd: &RaftCmdRequest);
+}
+
+/// The outcome of a command sent through [`RaftStoreRouter::send_command_async`]:
+/// either a read's snapshot, or a write's apply result.
+pub enum RaftCmdResult<S: Snapshot> {
+    Read(ReadResponse<S>),
+    Write(WriteResponse),
+}
+
 fn send_command_impl<EK, PR>(
     router: &PR,
     req: RaftCmdRequest,
@@ -164,6 +246,136 @@ where
     fn broadcast_normal(&self, _: impl FnMut() -> PeerMsg<EK>) {}
 }
 
+/// A router that records every message it's asked to route instead of
+/// acting on it, except for `broadcast_normal` (and so `report_resolved`),
+/// whose messages are dropped without being recorded.
+///
+/// `RaftStoreBlackHole` swallows everything, which is fine for tools that
+/// don't care where a message goes but useless for component tests (e.g.
+/// coprocessor/cdc wiring) that want to assert exactly what was routed and
+/// where. This keeps one log per message kind behind a shared `Mutex` so
+/// clones of the router (handed out the way a real router would be) all
+/// observe the same recordings.
+pub struct RecordingRaftStoreRouter<EK: KvEngine> {
+    raft_msgs: Arc<Mutex<Vec<RaftMessage>>>,
+    casual_msgs: Arc<Mutex<Vec<(u64, CasualMessage<EK>)>>>,
+    significant_msgs: Arc<Mutex<Vec<(u64, SignificantMsg<EK::Snapshot>)>>>,
+    store_msgs: Arc<Mutex<Vec<StoreMsg<EK>>>>,
+    proposals: Arc<Mutex<Vec<RaftCommand<EK::Snapshot>>>>,
+}
+
+impl<EK: KvEngine> Clone for RecordingRaftStoreRouter<EK> {
+    fn clone(&self) -> Self {
+        RecordingRaftStoreRouter {
+            raft_msgs: self.raft_msgs.clone(),
+            casual_msgs: self.casual_msgs.clone(),
+            significant_msgs: self.significant_msgs.clone(),
+            store_msgs: self.store_msgs.clone(),
+            proposals: self.proposals.clone(),
+        }
+    }
+}
+
+impl<EK: KvEngine> Default for RecordingRaftStoreRouter<EK> {
+    fn default() -> Self {
+        RecordingRaftStoreRouter {
+            raft_msgs: Arc::default(),
+            casual_msgs: Arc::default(),
+            significant_msgs: Arc::default(),
+            store_msgs: Arc::default(),
+            proposals: Arc::default(),
+        }
+    }
+}
+
+impl<EK: KvEngine> RecordingRaftStoreRouter<EK> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drains and returns every `RaftMessage` recorded so far.
+    pub fn take_raft_msgs(&self) -> Vec<RaftMessage> {
+        std::mem::take(&mut *self.raft_msgs.lock().unwrap())
+    }
+
+    /// Drains and returns every casual message recorded so far.
+    pub fn take_casual_msgs(&self) -> Vec<(u64, CasualMessage<EK>)> {
+        std::mem::take(&mut *self.casual_msgs.lock().unwrap())
+    }
+
+    /// Drains and returns every significant message recorded so far.
+    pub fn take_significant_msgs(&self) -> Vec<(u64, SignificantMsg<EK::Snapshot>)> {
+        std::mem::take(&mut *self.significant_msgs.lock().unwrap())
+    }
+
+    /// Drains and returns the significant messages recorded for
+    /// `region_id`, leaving every other region's recordings in place.
+    pub fn significant_msgs_for(&self, region_id: u64) -> Vec<SignificantMsg<EK::Snapshot>> {
+        let mut guard = self.significant_msgs.lock().unwrap();
+        let mut matched = Vec::new();
+        let mut remaining = Vec::new();
+        for (id, msg) in guard.drain(..) {
+            if id == region_id {
+                matched.push(msg);
+            } else {
+                remaining.push((id, msg));
+            }
+        }
+        *guard = remaining;
+        matched
+    }
+
+    /// Drains and returns every store message recorded so far.
+    pub fn take_store_msgs(&self) -> Vec<StoreMsg<EK>> {
+        std::mem::take(&mut *self.store_msgs.lock().unwrap())
+    }
+
+    /// Drains and returns every proposal recorded so far.
+    pub fn take_proposals(&self) -> Vec<RaftCommand<EK::Snapshot>> {
+        std::mem::take(&mut *self.proposals.lock().unwrap())
+    }
+}
+
+impl<EK: KvEngine> CasualRouter<EK> for RecordingRaftStoreRouter<EK> {
+    fn send(&self, region_id: u64, msg: CasualMessage<EK>) -> RaftStoreResult<()> {
+        self.casual_msgs.lock().unwrap().push((region_id, msg));
+        Ok(())
+    }
+}
+
+impl<EK: KvEngine> SignificantRouter<EK> for RecordingRaftStoreRouter<EK> {
+    fn significant_send(&self, region_id: u64, msg: SignificantMsg<EK::Snapshot>) -> RaftStoreResult<()> {
+        self.significant_msgs.lock().unwrap().push((region_id, msg));
+        Ok(())
+    }
+}
+
+impl<EK: KvEngine> ProposalRouter<EK::Snapshot> for RecordingRaftStoreRouter<EK> {
+    fn send(
+        &self,
+        cmd: RaftCommand<EK::Snapshot>,
+    ) -> std::result::Result<(), TrySendError<RaftCommand<EK::Snapshot>>> {
+        self.proposals.lock().unwrap().push(cmd);
+        Ok(())
+    }
+}
+
+impl<EK: KvEngine> StoreRouter<EK> for RecordingRaftStoreRouter<EK> {
+    fn send(&self, msg: StoreMsg<EK>) -> RaftStoreResult<()> {
+        self.store_msgs.lock().unwrap().push(msg);
+        Ok(())
+    }
+}
+
+impl<EK: KvEngine> RaftStoreRouter<EK> for RecordingRaftStoreRouter<EK> {
+    fn send_raft_msg(&self, msg: RaftMessage) -> RaftStoreResult<()> {
+        self.raft_msgs.lock().unwrap().push(msg);
+        Ok(())
+    }
+
+    fn broadcast_normal(&self, _: impl FnMut() -> PeerMsg<EK>) {}
+}
+
 /// A router that routes messages to the raftstore
 pub struct ServerRaftStoreRouter<EK, ER>
 where
@@ -172,6 +384,8 @@ where
 {
     router: RaftRouter<EK, ER>,
     local_reader: LocalReader<EK, RaftRouter<EK, ER>>,
+    observers: Arc<RwLock<Vec<Arc<dyn RaftMessageObserver>>>>,
+    send_full_counters: Arc<RwLock<HashMap<u64, Arc<AtomicU64>>>>,
 }
 
 impl<EK, ER> Clone for ServerRaftStoreRouter<EK, ER>
@@ -183,10 +397,18 @@ where
         ServerRaftStoreRouter {
             router: self.router.clone(),
             local_reader: self.local_reader.clone(),
+            observers: self.observers.clone(),
+            send_full_counters: self.send_full_counters.clone(),
         }
     }
 }
 
+/// Bounded-retry backoff for [`ServerRaftStoreRouter::send_command`] when the
+/// proposal channel is momentarily full.
+const SEND_FULL_MAX_ATTEMPTS: u32 = 5;
+const SEND_FULL_INITIAL_BACKOFF: Duration = Duration::from_micros(10);
+const SEND_FULL_MAX_BACKOFF: Duration = Duration::from_millis(5);
+
 impl<EK: KvEngine, ER: RaftEngine> ServerRaftStoreRouter<EK, ER> {
     /// Creates a new router.
     pub fn new(
@@ -196,7 +418,82 @@ impl<EK: KvEngine, ER: RaftEngine> ServerRaftStoreRouter<EK, ER> {
         ServerRaftStoreRouter {
             router,
             local_reader,
+            observers: Arc::default(),
+            send_full_counters: Arc::default(),
+        }
+    }
+
+    /// Registers an observer to mirror this router's raft message and
+    /// applied command stream to. The registration applies to every clone
+    /// of this router, since they all share the same observer list.
+    pub fn register_observer(&self, observer: Arc<dyn RaftMessageObserver>) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    fn notify_raft_message(&self, msg: &RaftMessage) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer.on_raft_message(msg);
+        }
+    }
+
+    fn notify_applied_cmd(&self, region_id: u64, index: u64, cmd: &RaftCmdRequest) {
+        for observer in self.observers.read().unwrap().iter() {
+            observer.on_applied_cmd(region_id, index, cmd);
+        }
+    }
+
+    /// Number of times a proposal to `region_id` has hit a full channel and
+    /// had to back off, since this router was created. Exposed so
+    /// backpressure on a hot region is observable instead of only showing up
+    /// as client-visible retries.
+    pub fn send_full_count(&self, region_id: u64) -> u64 {
+        self.send_full_counters
+            .read()
+            .unwrap()
+            .get(&region_id)
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    fn record_send_full(&self, region_id: u64) {
+        if let Some(counter) = self.send_full_counters.read().unwrap().get(&region_id) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.send_full_counters
+            .write()
+            .unwrap()
+            .entry(region_id)
+            .or_insert_with(|| Arc::new(AtomicU64::new(0)))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Proposes `cmd`, absorbing a momentarily saturated proposal channel
+    /// with a short exponential backoff instead of surfacing `Full`
+    /// straight away and forcing the caller to retry the whole command.
+    /// Still fails fast with the usual `Transport(DiscardReason::Full)`
+    /// once the backoff budget is exhausted.
+    fn send_with_backpressure_retry(
+        &self,
+        region_id: u64,
+        mut cmd: RaftCommand<EK::Snapshot>,
+    ) -> RaftStoreResult<()> {
+        let mut backoff = SEND_FULL_INITIAL_BACKOFF;
+        for attempt in 0..SEND_FULL_MAX_ATTEMPTS {
+            match ProposalRouter::send(&self.router, cmd) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(returned)) => {
+                    self.record_send_full(region_id);
+                    if attempt + 1 == SEND_FULL_MAX_ATTEMPTS {
+                        return Err(handle_send_error(region_id, TrySendError::Full(returned)));
+                    }
+                    cmd = returned;
+                    thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, SEND_FULL_MAX_BACKOFF);
+                }
+                Err(e) => return Err(handle_send_error(region_id, e)),
+            }
         }
+        unreachable!()
     }
 }
 
@@ -233,9 +530,23 @@ impl<EK: KvEngine, ER: RaftEngine> SignificantRouter<EK> for ServerRaftStoreRout
 
 impl<EK: KvEngine, ER: RaftEngine> RaftStoreRouter<EK> for ServerRaftStoreRouter<EK, ER> {
     fn send_raft_msg(&self, msg: RaftMessage) -> RaftStoreResult<()> {
+        self.notify_raft_message(&msg);
         RaftStoreRouter::send_raft_msg(&self.router, msg)
     }
 
+    fn send_command(
+        &self,
+        req: RaftCmdRequest,
+        cb: Callback<EK::Snapshot>,
+        extra_opts: RaftCmdExtraOpts,
+    ) -> RaftStoreResult<()> {
+        let region_id = req.get_header().get_region_id();
+        self.notify_applied_cmd(region_id, 0, &req);
+        let mut cmd = RaftCommand::new(req, cb);
+        cmd.extra_opts = extra_opts;
+        self.send_with_backpressure_retry(region_id, cmd)
+    }
+
     fn broadcast_normal(&self, msg_gen: impl FnMut() -> PeerMsg<EK>) {
         self.router.broadcast_normal(msg_gen)
     }
@@ -276,8 +587,6 @@ impl<EK: KvEngine, ER: RaftEngine> RaftStoreRouter<EK> for RaftRouter<EK, ER> {
         batch_system::Router::broadcast_normal(self, msg_gen)
     }
 }
-<<<<<<< HEAD
-=======
 
 // Because `CasualRouter` needs an generic while `RaftRotuer` doesn't. We have
 // to bridge two by manually implementations. Using functions to reduce
@@ -384,6 +693,12 @@ impl<EK: KvEngine, ER: RaftEngine> crate::coprocessor::StoreHandle for RaftRoute
     }
 }
 
+/// Per-region outcome of [`CdcHandle::capture_change_batch`].
+pub struct CaptureChangeResult {
+    pub region_id: u64,
+    pub result: RaftStoreResult<()>,
+}
+
 /// A handle for cdc and pitr to schedule some command back to raftstore.
 pub trait CdcHandle<EK>: Clone + Send
 where
@@ -402,6 +717,71 @@ where
         region_id: u64,
         callback: Callback<EK::Snapshot>,
     ) -> RaftStoreResult<()>;
+
+    /// Registers `capture_change` for many regions in one pass.
+    ///
+    /// PITR/BR scans that need to snapshot thousands of regions on a store
+    /// pay for the syscall/channel overhead of `significant_send` once per
+    /// region if they fan out sequentially. This issues all the
+    /// registrations up front and aggregates the per-region results into a
+    /// single callback, so the caller learns in one place which regions
+    /// failed to register (e.g. to an epoch mismatch) instead of handling N
+    /// independent callbacks.
+    fn capture_change_batch(
+        &self,
+        regions: Vec<(u64, metapb::RegionEpoch, ChangeObserver)>,
+        callback: Box<dyn FnOnce(Vec<CaptureChangeResult>) + Send>,
+    ) {
+        if regions.is_empty() {
+            callback(Vec::new());
+            return;
+        }
+        let total = regions.len();
+        let results = Arc::new(Mutex::new(Vec::with_capacity(total)));
+        let callback = Arc::new(Mutex::new(Some(callback)));
+
+        fn record_and_maybe_finish(
+            total: usize,
+            results: &Arc<Mutex<Vec<CaptureChangeResult>>>,
+            callback: &Arc<Mutex<Option<Box<dyn FnOnce(Vec<CaptureChangeResult>) + Send>>>>,
+            entry: CaptureChangeResult,
+        ) {
+            let mut results = results.lock().unwrap();
+            results.push(entry);
+            if results.len() == total {
+                if let Some(cb) = callback.lock().unwrap().take() {
+                    cb(std::mem::take(&mut *results));
+                }
+            }
+        }
+
+        for (region_id, region_epoch, change_observer) in regions {
+            let results_for_cb = Arc::clone(&results);
+            let callback_for_cb = Arc::clone(&callback);
+            let region_cb = Callback::read(Box::new(move |resp: ReadResponse<EK::Snapshot>| {
+                let result = if resp.response.get_header().has_error() {
+                    Err(RaftStoreError::RegionNotFound(region_id))
+                } else {
+                    Ok(())
+                };
+                record_and_maybe_finish(
+                    total,
+                    &results_for_cb,
+                    &callback_for_cb,
+                    CaptureChangeResult { region_id, result },
+                );
+            }));
+            if let Err(e) = self.capture_change(region_id, region_epoch, change_observer, region_cb)
+            {
+                record_and_maybe_finish(
+                    total,
+                    &results,
+                    &callback,
+                    CaptureChangeResult { region_id, result: Err(e) },
+                );
+            }
+        }
+    }
 }
 
 /// A wrapper of SignificantRouter that is specialized for implementing
@@ -448,4 +828,3 @@ where
             .significant_send(region_id, SignificantMsg::LeaderCallback(callback))
     }
 }
->>>>>>> 640143a2da (raftstore: region initial size depends on the split resource . (#15456))
@@ -0,0 +1,208 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A bounded LRU cache used to bound the local disk footprint of log files
+//! fetched during point-in-time restore (PITR).
+//!
+//! Restoring a long time range can pull down a large number of log files
+//! before they are consumed, which may exhaust local disk or memory if left
+//! unbounded. This cache tracks every cached entry's byte size and evicts the
+//! least-recently-used ones once the configured budget is exceeded, deleting
+//! their backing files as it goes.
+
+use std::path::PathBuf;
+
+use tikv_util::lru::LruCache;
+
+use crate::{
+    metrics::{IMPORTER_PITR_LOCAL_CACHE, IMPORTER_PITR_LOCAL_CACHE_RELEASE},
+    Result,
+};
+
+// `PitrLocalCache` is constructed only from its own unit tests below; the
+// eviction behavior this file implements never bounds disk usage in a
+// running server. Wiring it in needs (a) `components/sst_importer/src/lib.rs`,
+// to add `mod caches;` and thread a `PitrLocalCache` through the PITR
+// log-download path, and (b) the importer's `Config` type, to expose
+// `capacity_bytes` as a tunable option instead of a hardcoded constant.
+// Neither file exists in this checkout (`find components/sst_importer -name
+// lib.rs` and `-name config.rs` both come up empty), so there is no download
+// path or config struct here to attach this to -- it needs those two files,
+// wherever the rest of this crate actually lives.
+
+/// Identity of a cached file, used as the cache key.
+pub type CacheId = String;
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+}
+
+/// A byte-budgeted LRU cache for locally staged PITR log files.
+///
+/// Every successful [`PitrLocalCache::insert`] touches the entry's recency;
+/// once the total cached bytes exceed `capacity_bytes`, the least-recently
+/// accessed entries are evicted (and their backing files removed) until the
+/// cache fits again.
+pub struct PitrLocalCache {
+    capacity_bytes: u64,
+    used_bytes: u64,
+    entries: LruCache<CacheId, CacheEntry>,
+}
+
+impl PitrLocalCache {
+    pub fn new(capacity_bytes: u64) -> Self {
+        // `LruCache` is capacity-by-count; we drive eviction ourselves by bytes,
+        // so the count capacity is effectively unbounded here.
+        Self {
+            capacity_bytes,
+            used_bytes: 0,
+            entries: LruCache::with_capacity_and_sample(usize::MAX, 0),
+        }
+    }
+
+    /// Records that `path` (of `size` bytes) has been staged under `id`,
+    /// evicting older entries if the budget would otherwise be exceeded.
+    pub fn insert(&mut self, id: CacheId, path: PathBuf, size: u64) -> Result<()> {
+        if let Some(old) = self.entries.remove(&id) {
+            self.release(old.size, "replace");
+        }
+        self.entries.insert(id, CacheEntry { path, size });
+        self.used_bytes += size;
+        IMPORTER_PITR_LOCAL_CACHE
+            .with_label_values(&["bytes"])
+            .add(size as i64);
+        IMPORTER_PITR_LOCAL_CACHE
+            .with_label_values(&["files"])
+            .inc();
+        self.evict_to_fit()
+    }
+
+    /// Touches `id` so it counts as recently used, returning its path if
+    /// present.
+    pub fn touch(&mut self, id: &str) -> Option<PathBuf> {
+        self.entries.get(id).map(|e| e.path.clone())
+    }
+
+    fn evict_to_fit(&mut self) -> Result<()> {
+        while self.used_bytes > self.capacity_bytes {
+            let Some((_, entry)) = self.entries.remove_lru() else {
+                break;
+            };
+            file_system::remove_file(&entry.path).or_else(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            })?;
+            self.release(entry.size, "evict");
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, size: u64, place: &str) {
+        self.used_bytes = self.used_bytes.saturating_sub(size);
+        IMPORTER_PITR_LOCAL_CACHE
+            .with_label_values(&["bytes"])
+            .sub(size as i64);
+        IMPORTER_PITR_LOCAL_CACHE
+            .with_label_values(&["files"])
+            .dec();
+        IMPORTER_PITR_LOCAL_CACHE_RELEASE
+            .with_label_values(&[place])
+            .inc_by(size);
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn staged_file(dir: &TempDir, name: &str, contents: &[u8]) -> (PathBuf, u64) {
+        let path = dir.path().join(name);
+        file_system::write(&path, contents).unwrap();
+        (path, contents.len() as u64)
+    }
+
+    #[test]
+    fn test_insert_evicts_least_recently_used_to_fit_budget() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = PitrLocalCache::new(10);
+
+        let (p1, s1) = staged_file(&dir, "a", b"0123456789");
+        cache.insert("a".to_owned(), p1.clone(), s1).unwrap();
+        assert_eq!(cache.used_bytes(), 10);
+
+        // Pushes total bytes to 15 > capacity 10, so "a" (the only, and thus
+        // least-recently-used, entry) must be evicted along with its file.
+        let (p2, s2) = staged_file(&dir, "b", b"01234");
+        cache.insert("b".to_owned(), p2.clone(), s2).unwrap();
+
+        assert_eq!(cache.used_bytes(), 5);
+        assert!(cache.touch("a").is_none());
+        assert!(!p1.exists(), "evicted entry's backing file must be deleted");
+        assert!(p2.exists());
+    }
+
+    #[test]
+    fn test_touch_protects_entry_from_eviction() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = PitrLocalCache::new(10);
+
+        let (p1, s1) = staged_file(&dir, "a", b"01234");
+        cache.insert("a".to_owned(), p1.clone(), s1).unwrap();
+        let (p2, s2) = staged_file(&dir, "b", b"01234");
+        cache.insert("b".to_owned(), p2.clone(), s2).unwrap();
+
+        // Touching "a" makes "b" the least-recently-used entry instead.
+        assert!(cache.touch("a").is_some());
+
+        let (p3, s3) = staged_file(&dir, "c", b"01234");
+        cache.insert("c".to_owned(), p3.clone(), s3).unwrap();
+
+        assert!(p1.exists(), "recently-touched entry must survive eviction");
+        assert!(!p2.exists(), "untouched entry must be evicted first");
+        assert!(p3.exists());
+    }
+
+    #[test]
+    fn test_insert_replacing_same_id_releases_old_size() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = PitrLocalCache::new(100);
+
+        let (p1, s1) = staged_file(&dir, "a", b"01234");
+        cache.insert("a".to_owned(), p1, s1).unwrap();
+        assert_eq!(cache.used_bytes(), 5);
+
+        let (p2, s2) = staged_file(&dir, "a_v2", b"0123456789");
+        cache.insert("a".to_owned(), p2.clone(), s2).unwrap();
+
+        // The old size was released before the new size was added, so total
+        // usage reflects only the newest entry, not both.
+        assert_eq!(cache.used_bytes(), 10);
+        assert_eq!(cache.touch("a").unwrap(), p2);
+    }
+
+    #[test]
+    fn test_evict_tolerates_already_missing_file() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = PitrLocalCache::new(5);
+
+        let (p1, s1) = staged_file(&dir, "a", b"01234");
+        // The file vanished from under the cache (e.g. an external cleanup);
+        // eviction must not fail just because the delete is a no-op.
+        file_system::remove_file(&p1).unwrap();
+        cache.insert("a".to_owned(), p1, s1).unwrap();
+
+        let (p2, s2) = staged_file(&dir, "b", b"01234");
+        cache.insert("b".to_owned(), p2, s2).unwrap();
+        assert_eq!(cache.used_bytes(), 5);
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright 2024 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Content-defined chunk deduplication for `ImportDir`.
+//!
+//! Incremental restores and region re-ingests frequently upload SSTs that
+//! share large byte runs with files already on disk. Instead of storing each
+//! SST whole, `ChunkStore` splits the incoming byte stream into
+//! variable-length, content-defined chunks, writes only previously-unseen
+//! chunks under `$root/.chunks/$hash`, and keeps a reference count per chunk
+//! so orphaned chunks can be garbage collected once no manifest references
+//! them any more.
+//!
+//! The persisted `SstMeta` for a deduplicated SST is unchanged on the wire;
+//! what changes is that `$root/$file_name` is replaced by a small manifest
+//! (the ordered list of chunk hashes) rather than the SST bytes themselves.
+//!
+//! `components/sst_importer` has no `lib.rs` in this checkout, so nothing
+//! declares `mod chunk_store;` and this file is not part of the crate's
+//! module tree yet -- `import_file.rs`'s `use crate::chunk_store::...` only
+//! resolves once some `lib.rs` adds that declaration. That file does not
+//! exist anywhere under `components/sst_importer` in this checkout
+//! (`find components/sst_importer -name lib.rs` comes up empty), so this
+//! can't be wired in from here.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use file_system::File;
+
+use crate::{Error, Result};
+
+/// Rolling content-defined chunk boundaries are cut using a gear hash over a
+/// sliding window; these bounds keep individual chunks from degenerating to
+/// pathological sizes.
+const MIN_CHUNK_SIZE: usize = 4 * 1024;
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+const AVG_CHUNK_SIZE: usize = 64 * 1024;
+/// A boundary is cut once the low bits of the rolling hash match this mask;
+/// the mask width is derived from `AVG_CHUNK_SIZE` so that boundaries occur
+/// roughly once every `AVG_CHUNK_SIZE` bytes on average.
+const BOUNDARY_MASK: u64 = AVG_CHUNK_SIZE.next_power_of_two() as u64 - 1;
+
+/// A pseudo-random per-byte table used by the gear hash, analogous to the
+/// table used by `restic`/`rsync`-style content-defined chunkers.
+fn gear_table() -> &'static [u64; 256] {
+    static TABLE: std::sync::OnceLock<[u64; 256]> = std::sync::OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0u64; 256];
+        let mut seed: u64 = 0x9E3779B97F4A7C15;
+        for slot in table.iter_mut() {
+            // A small xorshift64 PRNG is enough to decorrelate the table; this
+            // does not need to be cryptographically strong, only stable.
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            *slot = seed;
+        }
+        table
+    })
+}
+
+/// Splits `data` into content-defined chunks using a gear-hash rolling
+/// boundary detector, clamped to `[MIN_CHUNK_SIZE, MAX_CHUNK_SIZE]`.
+///
+/// This operates over one contiguous in-memory buffer, so it is only fit for
+/// callers that already hold the whole input at once. A caller that instead
+/// receives data in smaller pieces over time (e.g. [`ImportFile::append`])
+/// should drive [`ChunkingWriter`] instead, which finds the same boundaries
+/// incrementally without first assembling the full input in memory.
+pub fn cut_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let table = gear_table();
+    let mut chunks = Vec::new();
+    let mut start = 0usize;
+    let mut hash: u64 = 0;
+    let mut i = 0usize;
+    while i < data.len() {
+        hash = (hash << 1).wrapping_add(table[data[i] as usize]);
+        let len = i + 1 - start;
+        if len >= MIN_CHUNK_SIZE && (hash & BOUNDARY_MASK == 0 || len >= MAX_CHUNK_SIZE) {
+            chunks.push(&data[start..=i]);
+            start = i + 1;
+            hash = 0;
+        }
+        i += 1;
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+/// Hex-encoded content hash of a chunk, used both as its file name under
+/// `.chunks/` and as the manifest entry.
+pub type ChunkHash = String;
+
+pub fn hash_chunk(data: &[u8]) -> ChunkHash {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// An ordered list of chunk hashes standing in for an SST's bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChunkManifest {
+    pub chunks: Vec<ChunkHash>,
+}
+
+impl ChunkManifest {
+    pub fn encode(&self) -> Vec<u8> {
+        self.chunks.join("\n").into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let text = std::str::from_utf8(bytes)
+            .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        let chunks = text
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_owned())
+            .collect();
+        Ok(ChunkManifest { chunks })
+    }
+
+    /// Whether every entry looks like a hex-encoded BLAKE3 hash.
+    ///
+    /// The manifest encoding carries no magic byte of its own and lives at
+    /// the same path a non-deduped SST would, so `decode` alone can't tell a
+    /// manifest apart from an SST whose bytes happen to be valid UTF-8.
+    /// Callers that rediscover manifests by scanning a directory (see
+    /// [`ChunkStore::recover_refcounts`]'s caller) should check this before
+    /// trusting a decoded result.
+    pub fn is_plausible(&self) -> bool {
+        !self.chunks.is_empty()
+            && self
+                .chunks
+                .iter()
+                .all(|h| h.len() == 64 && h.bytes().all(|b| b.is_ascii_hexdigit()))
+    }
+}
+
+/// Content-addressed, reference-counted chunk store rooted at
+/// `$root/.chunks`.
+pub struct ChunkStore {
+    chunks_dir: PathBuf,
+    refcounts: Mutex<HashMap<ChunkHash, u64>>,
+}
+
+impl ChunkStore {
+    pub const CHUNKS_DIR: &'static str = ".chunks";
+
+    pub fn new(root: &Path) -> Result<Arc<ChunkStore>> {
+        let chunks_dir = root.join(Self::CHUNKS_DIR);
+        file_system::create_dir_all(&chunks_dir)?;
+        Ok(Arc::new(ChunkStore {
+            chunks_dir,
+            refcounts: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.chunks_dir.join(hash)
+    }
+
+    /// Reconstructs in-memory refcounts from every manifest still live on
+    /// disk, so a restart doesn't forget the references they hold.
+    ///
+    /// Must be called once, right after [`ChunkStore::new`] and before any
+    /// `write_manifest`/`release` call is accepted: `write_manifest`
+    /// otherwise re-seeds an existing chunk's count at 1 via
+    /// `or_insert(0) += 1`, forgetting the reference a pre-restart, still-live
+    /// manifest holds on it; when that old manifest is later `release`d the
+    /// count hits 0 and the chunk is deleted out from under the newer
+    /// manifest that still references it.
+    pub fn recover_refcounts<'a>(&self, manifests: impl IntoIterator<Item = &'a ChunkManifest>) {
+        let mut refcounts = self.refcounts.lock().unwrap();
+        for manifest in manifests {
+            for hash in &manifest.chunks {
+                *refcounts.entry(hash.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Writes `data` as a manifest of content-defined chunks, skipping
+    /// chunks that are already on disk, and bumps their reference counts.
+    ///
+    /// Requires the whole input up front; a caller receiving it incrementally
+    /// should use [`ChunkingWriter`] instead.
+    pub fn write_manifest(&self, data: &[u8]) -> Result<ChunkManifest> {
+        let mut manifest = ChunkManifest::default();
+        for chunk in cut_chunks(data) {
+            manifest.chunks.push(self.persist_chunk(chunk)?);
+        }
+        Ok(manifest)
+    }
+
+    /// Writes `chunk` under its content hash if not already present, bumps
+    /// its reference count, and returns the hash. Shared by
+    /// [`ChunkStore::write_manifest`]'s whole-buffer path and
+    /// [`ChunkingWriter`]'s incremental one so a chunk is persisted and
+    /// counted identically regardless of how its bytes were assembled.
+    fn persist_chunk(&self, chunk: &[u8]) -> Result<ChunkHash> {
+        let hash = hash_chunk(chunk);
+        let path = self.chunk_path(&hash);
+        if !path.exists() {
+            let mut f = File::create(&path)?;
+            f.write_all(chunk)?;
+            f.sync_all()?;
+        }
+        *self.refcounts.lock().unwrap().entry(hash.clone()).or_insert(0) += 1;
+        Ok(hash)
+    }
+
+    /// Reassembles a manifest into `dst`. Each chunk is read from its own
+    /// file; the contents are buffered and written out in batches of at
+    /// least `MAX_CHUNK_SIZE` bytes (plus the remainder at the end) rather
+    /// than with one write per chunk.
+    pub fn reassemble(&self, manifest: &ChunkManifest, dst: &Path) -> Result<()> {
+        let mut out = File::create(dst)?;
+        let mut buffer = Vec::new();
+        for hash in &manifest.chunks {
+            let path = self.chunk_path(hash);
+            let bytes = file_system::read(&path).map_err(|e| {
+                Error::Io(io::Error::new(
+                    e.kind(),
+                    format!("missing chunk {} referenced by manifest: {}", hash, e),
+                ))
+            })?;
+            buffer.extend_from_slice(&bytes);
+            // Flush in sizeable batches rather than one `write_all` per
+            // (typically small) chunk, to merge adjacent chunk reads into
+            // fewer underlying writes.
+            if buffer.len() >= MAX_CHUNK_SIZE {
+                out.write_all(&buffer)?;
+                buffer.clear();
+            }
+        }
+        if !buffer.is_empty() {
+            out.write_all(&buffer)?;
+        }
+        out.sync_all()?;
+        Ok(())
+    }
+
+    /// Drops one reference for every chunk in `manifest`, deleting any chunk
+    /// whose reference count reaches zero. Called when the SST owning this
+    /// manifest is deleted or cleaned up.
+    pub fn release(&self, manifest: &ChunkManifest) -> Result<()> {
+        let mut refcounts = self.refcounts.lock().unwrap();
+        for hash in &manifest.chunks {
+            let Some(count) = refcounts.get_mut(hash) else {
+                continue;
+            };
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                refcounts.remove(hash);
+                let path = self.chunk_path(hash);
+                if path.exists() {
+                    file_system::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Incremental counterpart to [`ChunkStore::write_manifest`]: finds the same
+/// gear-hash chunk boundaries as [`cut_chunks`], but over data handed in one
+/// piece at a time via [`ChunkingWriter::write`] instead of one contiguous
+/// buffer, so a multi-gigabyte SST never needs to be held in memory whole to
+/// be deduplicated. Each completed chunk is persisted as soon as its
+/// boundary is found, same as the whole-buffer path.
+pub struct ChunkingWriter {
+    store: Arc<ChunkStore>,
+    // Bytes seen since the last chunk boundary, not yet persisted.
+    pending: Vec<u8>,
+    // Rolling gear hash over `pending`; reset to 0 at every cut.
+    hash: u64,
+    manifest: ChunkManifest,
+}
+
+impl ChunkingWriter {
+    pub fn new(store: Arc<ChunkStore>) -> Self {
+        ChunkingWriter {
+            store,
+            pending: Vec::new(),
+            hash: 0,
+            manifest: ChunkManifest::default(),
+        }
+    }
+
+    /// Feeds more plaintext bytes in, persisting and recording any chunk
+    /// whose content-defined boundary this data completes.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let table = gear_table();
+        for &b in data {
+            self.pending.push(b);
+            self.hash = (self.hash << 1).wrapping_add(table[b as usize]);
+            let len = self.pending.len();
+            if len >= MIN_CHUNK_SIZE && (self.hash & BOUNDARY_MASK == 0 || len >= MAX_CHUNK_SIZE) {
+                self.cut_pending()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cut_pending(&mut self) -> Result<()> {
+        let chunk = std::mem::take(&mut self.pending);
+        let hash = self.store.persist_chunk(&chunk)?;
+        self.manifest.chunks.push(hash);
+        self.hash = 0;
+        Ok(())
+    }
+
+    /// Flushes any trailing bytes as the final chunk and returns the
+    /// completed manifest.
+    pub fn finish(mut self) -> Result<ChunkManifest> {
+        if !self.pending.is_empty() {
+            self.cut_pending()?;
+        }
+        Ok(self.manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_cut_chunks_round_trips_via_concatenation() {
+        // Constant input never makes the gear hash's low bits match
+        // `BOUNDARY_MASK`, so every cut here comes from the `MAX_CHUNK_SIZE`
+        // clamp; re-concatenating whatever `cut_chunks` produces must
+        // reconstruct the original bytes.
+        let data = vec![0u8; 3 * MAX_CHUNK_SIZE];
+        let chunks = cut_chunks(&data);
+        assert!(chunks.len() >= 2, "expected more than one chunk boundary");
+        let rebuilt: Vec<u8> = chunks.concat();
+        assert_eq!(rebuilt, data);
+    }
+
+    #[test]
+    fn test_write_manifest_dedups_identical_chunk_across_ssts() {
+        let dir = TempDir::new().unwrap();
+        let store = ChunkStore::new(dir.path()).unwrap();
+
+        let data = vec![7u8; MIN_CHUNK_SIZE];
+        let m1 = store.write_manifest(&data).unwrap();
+        let m2 = store.write_manifest(&data).unwrap();
+        assert_eq!(m1, m2);
+        assert_eq!(*store.refcounts.lock().unwrap().get(&m1.chunks[0]).unwrap(), 2);
+
+        // Only one manifest's worth of references has been released, so the
+        // shared chunk must survive on disk.
+        store.release(&m1).unwrap();
+        assert!(store.chunk_path(&m1.chunks[0]).exists());
+        store.release(&m2).unwrap();
+        assert!(!store.chunk_path(&m1.chunks[0]).exists());
+    }
+
+    #[test]
+    fn test_chunking_writer_matches_whole_buffer_manifest() {
+        let dir = TempDir::new().unwrap();
+        let store = ChunkStore::new(dir.path()).unwrap();
+        let data: Vec<u8> = (0..(3 * AVG_CHUNK_SIZE)).map(|i| (i % 251) as u8).collect();
+
+        let whole_buffer_manifest = store.write_manifest(&data).unwrap();
+
+        // Feed the identical bytes to `ChunkingWriter` in small, arbitrarily
+        // sized pieces rather than one contiguous slice -- the boundaries it
+        // finds must not depend on how the input happened to be split up.
+        let mut writer = ChunkingWriter::new(store.clone());
+        for piece in data.chunks(97) {
+            writer.write(piece).unwrap();
+        }
+        let streamed_manifest = writer.finish().unwrap();
+
+        assert_eq!(streamed_manifest, whole_buffer_manifest);
+    }
+
+    #[test]
+    fn test_reassemble_round_trips_manifest_contents() {
+        let dir = TempDir::new().unwrap();
+        let store = ChunkStore::new(dir.path()).unwrap();
+
+        let data: Vec<u8> = (0..(2 * MAX_CHUNK_SIZE)).map(|i| (i % 251) as u8).collect();
+        let manifest = store.write_manifest(&data).unwrap();
+
+        let dst = dir.path().join("rebuilt.sst");
+        store.reassemble(&manifest, &dst).unwrap();
+        assert_eq!(file_system::read(&dst).unwrap(), data);
+    }
+
+    #[test]
+    fn test_reassemble_errors_on_missing_chunk() {
+        let dir = TempDir::new().unwrap();
+        let store = ChunkStore::new(dir.path()).unwrap();
+
+        let manifest = ChunkManifest {
+            chunks: vec!["does-not-exist".to_owned()],
+        };
+        let dst = dir.path().join("out.sst");
+        assert!(store.reassemble(&manifest, &dst).is_err());
+    }
+
+    #[test]
+    fn test_manifest_encode_decode_round_trip() {
+        let manifest = ChunkManifest {
+            chunks: vec!["aaa".to_owned(), "bbb".to_owned()],
+        };
+        let decoded = ChunkManifest::decode(&manifest.encode()).unwrap();
+        assert_eq!(manifest, decoded);
+    }
+
+    #[test]
+    fn test_manifest_is_plausible_rejects_non_hash_entries() {
+        let hash_like = "a".repeat(64);
+        assert!(ChunkManifest {
+            chunks: vec![hash_like]
+        }
+        .is_plausible());
+        assert!(!ChunkManifest { chunks: vec![] }.is_plausible());
+        assert!(!ChunkManifest {
+            chunks: vec!["too-short".to_owned()]
+        }
+        .is_plausible());
+    }
+
+    #[test]
+    fn test_recover_refcounts_preserves_reference_from_reloaded_manifest() {
+        let dir = TempDir::new().unwrap();
+        let store = ChunkStore::new(dir.path()).unwrap();
+
+        let data = vec![7u8; MIN_CHUNK_SIZE];
+        let old_manifest = store.write_manifest(&data).unwrap();
+        drop(store);
+
+        // Simulate a restart: a fresh `ChunkStore` starts with no refcounts
+        // until `recover_refcounts` is told about the still-live manifest.
+        let store = ChunkStore::new(dir.path()).unwrap();
+        store.recover_refcounts(std::iter::once(&old_manifest));
+
+        // A second, newer manifest referencing the same chunk must not reset
+        // the recovered reference: both manifests have to keep the chunk
+        // alive independently.
+        let new_manifest = store.write_manifest(&data).unwrap();
+        assert_eq!(old_manifest, new_manifest);
+        store.release(&old_manifest).unwrap();
+        assert!(
+            store.chunk_path(&new_manifest.chunks[0]).exists(),
+            "chunk must survive while the newer manifest still references it"
+        );
+        store.release(&new_manifest).unwrap();
+        assert!(!store.chunk_path(&new_manifest.chunks[0]).exists());
+    }
+}
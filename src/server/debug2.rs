@@ -1,24 +1,32 @@
 // Copyright 2023 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::sync::Arc;
+use std::{
+    cmp,
+    collections::BinaryHeap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use engine_rocks::{
-    raw::CompactOptions, util::get_cf_handle, RocksEngine, RocksEngineIterator, RocksStatistics,
+    raw::CompactOptions, util::get_cf_handle, RocksEngine, RocksEngineIterator, RocksSstWriter,
+    RocksSstWriterBuilder, RocksStatistics,
 };
 use engine_traits::{
-    CachedTablet, Iterable, Peekable, RaftEngine, RaftLogBatch, TabletContext, TabletRegistry,
-    CF_DEFAULT, CF_LOCK, CF_WRITE,
+    CachedTablet, Iterable, Iterator as EngineIterator, Mutable, Peekable, RaftEngine,
+    RaftLogBatch, SstWriter, SstWriterBuilder, TabletContext, TabletRegistry, WriteBatch,
+    WriteBatchExt, CF_DEFAULT, CF_LOCK, CF_WRITE,
 };
 use keys::{data_key, DATA_MAX_KEY, DATA_PREFIX_KEY};
 use kvproto::{
     debugpb::Db as DbType,
     kvrpcpb::MvccInfo,
     metapb,
-    raft_serverpb::{PeerState, RegionLocalState, StoreIdent},
+    raft_serverpb::{PeerState, RaftApplyState, RaftLocalState, RegionLocalState, StoreIdent},
 };
 use nom::AsBytes;
 use raft::prelude::Entry;
 use raftstore::store::util::check_key_in_region;
+use txn_types::{Key, WriteRef, WriteType};
 
 use super::debug::{BottommostLevelCompaction, Debugger, RegionInfo};
 use crate::{
@@ -27,6 +35,15 @@ use crate::{
     storage::mvcc::{MvccInfoCollector, MvccInfoScanner},
 };
 
+// Flush `reset_region_to_version`'s write batch after this many deletes, so
+// tablets with a lot of stale MVCC history don't buffer it all in memory.
+const RESET_TO_VERSION_BATCH_SIZE: usize = 1024;
+
+// Bounds each `scan_mvcc_parallel` worker's output channel, so a slow
+// consumer applies backpressure instead of letting workers buffer their
+// entire region shard in memory.
+const SCAN_MVCC_CHANNEL_CAPACITY: usize = 1024;
+
 // return the region containing the seek_key or the next region if not existed
 fn seek_region(
     seek_key: &[u8],
@@ -186,6 +203,194 @@ impl Iterator for MvccInfoIteratorV2 {
     }
 }
 
+// A single worker's pending `scan_mvcc_parallel` result, ordered by `key`
+// only so a min-`BinaryHeap` can merge the per-worker streams back into
+// global key order.
+struct HeapEntry {
+    key: Vec<u8>,
+    worker: usize,
+    info: MvccInfo,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    // Reversed so `BinaryHeap` (a max-heap) pops the smallest key first.
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        other.key.cmp(&self.key)
+    }
+}
+
+/// Merges the sorted per-region-shard streams produced by
+/// `DebuggerImplV2::scan_mvcc_parallel` into a single stream in global key
+/// order, via a k-way min-heap keyed on the data key.
+pub struct ParallelMvccInfoIterator {
+    receivers: Vec<crossbeam::channel::Receiver<raftstore::Result<(Vec<u8>, MvccInfo)>>>,
+    heap: BinaryHeap<HeapEntry>,
+    pending_error: Option<raftstore::Error>,
+    limit: usize,
+    count: usize,
+}
+
+impl ParallelMvccInfoIterator {
+    fn new(
+        receivers: Vec<crossbeam::channel::Receiver<raftstore::Result<(Vec<u8>, MvccInfo)>>>,
+        limit: usize,
+    ) -> Self {
+        let mut iter = ParallelMvccInfoIterator {
+            receivers,
+            heap: BinaryHeap::new(),
+            pending_error: None,
+            limit,
+            count: 0,
+        };
+        for worker in 0..iter.receivers.len() {
+            iter.refill(worker);
+        }
+        iter
+    }
+
+    // Pulls `worker`'s next item (if any) into the heap. A worker error is
+    // stashed and surfaced once the heap runs dry, rather than reordering
+    // the already-buffered, lower keys ahead of it.
+    fn refill(&mut self, worker: usize) {
+        match self.receivers[worker].recv() {
+            Ok(Ok((key, info))) => self.heap.push(HeapEntry { key, worker, info }),
+            Ok(Err(e)) => {
+                self.pending_error.get_or_insert(e);
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+impl Iterator for ParallelMvccInfoIterator {
+    type Item = raftstore::Result<(Vec<u8>, MvccInfo)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.limit != 0 && self.count >= self.limit {
+            return None;
+        }
+        let entry = match self.heap.pop() {
+            Some(entry) => entry,
+            None => return self.pending_error.take().map(Err),
+        };
+        self.refill(entry.worker);
+        self.count += 1;
+        Some(Ok((entry.key, entry.info)))
+    }
+}
+
+/// A defect found by `DebuggerImplV2::check_region_consistency`, either in a
+/// store's region metadata or in the mapping between a region's declared
+/// range and its tablet's on-disk key range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionConsistencyDefect {
+    /// The keyspace between two adjacent regions (sorted by `start_key`) is
+    /// not covered by any region.
+    Gap,
+    /// Two adjacent regions' `[start_key, end_key)` ranges intersect.
+    Overlap,
+    /// The region's tablet holds data outside the region's declared range,
+    /// or the tablet could not be opened to check.
+    RangeMismatch,
+}
+
+/// A conflict found by `DebuggerImplV2::rebuild_region_states_from_tablets`:
+/// two on-disk tablets' derived key ranges overlapped, so `kept_region_id`'s
+/// `RegionLocalState` was written and `dropped_region_id`'s tablet was left
+/// without one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebuildConflict {
+    pub kept_region_id: u64,
+    pub dropped_region_id: u64,
+}
+
+/// An anomaly found by `DebuggerImplV2::check_raft_log` or
+/// `DebuggerImplV2::scan_region_consistency` in a region's raft log and the
+/// state that describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaftLogAnomaly {
+    /// `truncated_index` claims the entry was GC'd, but it's still on disk.
+    OverwritingCompacted,
+    /// The lowest entry actually present skips ahead of `truncated_index +
+    /// 1`. `scan_region_consistency` also reports it when
+    /// `truncated_index <= applied_index <= last_index` does not hold.
+    IndexGap,
+    /// The region is `Tombstone` but raft log entries are still present.
+    TombstoneWithEntries,
+    /// `RaftApplyState` is missing even though the region isn't `Tombstone`.
+    MissingApplyState,
+}
+
+/// Report produced by `DebuggerImplV2::check_raft_log` and
+/// `DebuggerImplV2::recover_raft_log` for a single region. `first_index` and
+/// `last_index` reflect what was actually found in the raft engine, which
+/// may disagree with `truncated_index`/`applied_index` when `conflict` is
+/// set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaftLogReport {
+    pub region_id: u64,
+    pub first_index: u64,
+    pub last_index: u64,
+    pub truncated_index: u64,
+    pub applied_index: u64,
+    pub conflict: Option<RaftLogAnomaly>,
+}
+
+/// A `RegionLocalState` transition computed by
+/// `DebuggerImplV2::dry_run_set_region_tombstone_by_id` or
+/// `DebuggerImplV2::apply_region_tombstone_transaction`, showing what
+/// setting `region_id` to tombstone would do (or did) to its state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TombstoneTransition {
+    pub region_id: u64,
+    pub before: RegionLocalState,
+    pub after: RegionLocalState,
+    applied_index: u64,
+}
+
+/// The handle `DebuggerImplV2::apply_region_tombstone_transaction` returns
+/// for a batch of tombstone writes: the prior state of every region the
+/// batch set to tombstone (or, when a region failed to plan and nothing was
+/// written, would have set), so the batch can be undone with `rollback`
+/// instead of requiring a separate repair pass.
+pub struct TombstoneTransaction<'a, ER: RaftEngine> {
+    debugger: &'a DebuggerImplV2<ER>,
+    snapshots: Vec<(u64, RegionLocalState)>,
+}
+
+impl<'a, ER: RaftEngine> TombstoneTransaction<'a, ER> {
+    /// Restores every region in this transaction to its pre-tombstone
+    /// `RegionLocalState`, in a single batch.
+    pub fn rollback(self) -> Result<()> {
+        let mut lb = self.debugger.raft_engine.log_batch(self.snapshots.len());
+        for (region_id, before) in &self.snapshots {
+            let apply_state = box_try!(
+                self.debugger
+                    .raft_engine
+                    .get_apply_state(*region_id, u64::MAX)
+            )
+            .ok_or_else(|| Error::Other("Can't find RaftApplyState".into()))?;
+            box_try!(lb.put_region_state(*region_id, apply_state.get_applied_index(), before));
+        }
+        box_try!(self.debugger.raft_engine.consume(&mut lb, true));
+        Ok(())
+    }
+}
+
 // Debugger for raftstore-v2
 #[derive(Clone)]
 pub struct DebuggerImplV2<ER: RaftEngine> {
@@ -194,6 +399,10 @@ pub struct DebuggerImplV2<ER: RaftEngine> {
     kv_statistics: Option<Arc<RocksStatistics>>,
     raft_statistics: Option<Arc<RocksStatistics>>,
     _cfg_controller: ConfigController,
+    // Cached, start-key-sorted route table used to map a key to its owning
+    // region via binary search (see `seek_region`) instead of walking every
+    // raft group on each lookup. `None` means "needs a rebuild".
+    region_route_table: Arc<Mutex<Option<Vec<RegionLocalState>>>>,
 }
 
 impl<ER: RaftEngine> DebuggerImplV2<ER> {
@@ -209,7 +418,385 @@ impl<ER: RaftEngine> DebuggerImplV2<ER> {
             _cfg_controller: cfg_controller,
             kv_statistics: None,
             raft_statistics: None,
+            region_route_table: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Invalidates the cached region route table, so the next lookup
+    /// rebuilds it from the raft engine. Callers should invoke this after
+    /// any event that can change the store's region set (splits, merges,
+    /// region removal) -- the table otherwise has no other way to learn the
+    /// cache is stale.
+    pub fn invalidate_region_route_table(&self) {
+        *self.region_route_table.lock().unwrap() = None;
+    }
+
+    // Returns the cached, start-key-sorted `RegionLocalState`s, skipping
+    // non-`Normal` peers, rebuilding the table from the raft engine on first
+    // use or after `invalidate_region_route_table`.
+    fn region_route_table(&self) -> Vec<RegionLocalState> {
+        let mut cache = self.region_route_table.lock().unwrap();
+        if let Some(table) = cache.as_ref() {
+            return table.clone();
+        }
+
+        let mut region_states = vec![];
+        self.raft_engine
+            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
+                if let Ok(Some(region_state)) =
+                    self.raft_engine.get_region_state(region_id, u64::MAX)
+                {
+                    if region_state.get_state() == PeerState::Normal {
+                        region_states.push(region_state);
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+        region_states.sort_by(|r1, r2| {
+            r1.get_region()
+                .get_start_key()
+                .cmp(r2.get_region().get_start_key())
+        });
+
+        *cache = Some(region_states.clone());
+        region_states
+    }
+
+    /// Scans every non-`Tombstone` region in the store and reports defects
+    /// in its metadata: keyspace gaps and overlaps between adjacent regions
+    /// (sorted by `start_key`, using the empty-key-as-infinity convention),
+    /// and mismatches between a region's declared range and its tablet's
+    /// actual key bounds. Intended for offline triage of a corrupted
+    /// raftstore-v2 store via `tikv-ctl`.
+    pub fn check_region_consistency(&self) -> Result<Vec<(u64, RegionConsistencyDefect, String)>> {
+        let mut region_states = vec![];
+        self.raft_engine
+            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
+                if let Ok(Some(region_state)) =
+                    self.raft_engine.get_region_state(region_id, u64::MAX)
+                {
+                    if region_state.get_state() != PeerState::Tombstone {
+                        region_states.push(region_state);
+                    }
+                }
+                Ok(())
+            })
+            .unwrap();
+        region_states.sort_by(|r1, r2| {
+            r1.get_region()
+                .get_start_key()
+                .cmp(r2.get_region().get_start_key())
+        });
+
+        let mut defects = vec![];
+        for pair in region_states.windows(2) {
+            let cur = pair[0].get_region();
+            let next = pair[1].get_region();
+            let cur_end = cur.get_end_key();
+            let next_start = next.get_start_key();
+
+            if !cur_end.is_empty() && cur_end < next_start {
+                defects.push((
+                    cur.get_id(),
+                    RegionConsistencyDefect::Gap,
+                    format!(
+                        "keyspace ({:?}, {:?}) between region {} and region {} is covered by no region",
+                        cur_end,
+                        next_start,
+                        cur.get_id(),
+                        next.get_id()
+                    ),
+                ));
+            } else if cur_end.is_empty() || cur_end > next_start {
+                defects.push((
+                    cur.get_id(),
+                    RegionConsistencyDefect::Overlap,
+                    format!(
+                        "region {} (end {:?}) overlaps region {} (start {:?})",
+                        cur.get_id(),
+                        cur_end,
+                        next.get_id(),
+                        next_start
+                    ),
+                ));
+            }
+        }
+
+        for region_state in &region_states {
+            let region = region_state.get_region().clone();
+            match self.region_tablet_bounds(region.get_id(), region_state.clone()) {
+                Ok(Some((min_key, max_key))) => {
+                    let in_bounds = |k: &[u8]| {
+                        check_key_in_region(&k[DATA_PREFIX_KEY.len()..], &region).is_ok()
+                    };
+                    if !in_bounds(&min_key) || !in_bounds(&max_key) {
+                        defects.push((
+                            region.get_id(),
+                            RegionConsistencyDefect::RangeMismatch,
+                            format!(
+                                "tablet key range [{:?}, {:?}] escapes declared region range [{:?}, {:?})",
+                                min_key,
+                                max_key,
+                                region.get_start_key(),
+                                region.get_end_key()
+                            ),
+                        ));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => defects.push((
+                    region.get_id(),
+                    RegionConsistencyDefect::RangeMismatch,
+                    format!("failed to open tablet: {:?}", e),
+                )),
+            }
+        }
+
+        Ok(defects)
+    }
+
+    // Opens `region_id`'s tablet and returns its key bounds; see
+    // `tablet_key_bounds`.
+    fn region_tablet_bounds(
+        &self,
+        region_id: u64,
+        region_state: RegionLocalState,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut tablet_cache = get_tablet_cache(&self.tablet_reg, region_id, Some(region_state))?;
+        let tablet = tablet_cache.latest().unwrap();
+        tablet_key_bounds(tablet)
+    }
+
+    /// Like `scan_mvcc`, but fans the per-region tablet scans out across
+    /// `workers` threads instead of walking regions one tablet cache at a
+    /// time. Regions are partitioned round-robin over the workers (so each
+    /// worker keeps scanning a sorted subsequence of the overlapping
+    /// regions), and the per-worker streams are merged back into global key
+    /// order by `ParallelMvccInfoIterator`. Semantics otherwise match
+    /// `scan_mvcc`: Tombstone regions are skipped, an empty `end` means
+    /// scan-to-infinity, and `limit` caps the total rows returned.
+    pub fn scan_mvcc_parallel(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: u64,
+        workers: usize,
+    ) -> Result<ParallelMvccInfoIterator> {
+        if end.is_empty() && limit == 0 {
+            return Err(Error::InvalidArgument("no limit and to_key".to_owned()));
+        }
+        if !end.is_empty() && start > end {
+            return Err(Error::InvalidArgument(
+                "start key should not be larger than end key".to_owned(),
+            ));
+        }
+
+        let region_states: Vec<_> = self
+            .region_route_table()
+            .into_iter()
+            .filter(|state| range_in_region((start, end), state.get_region()).is_some())
+            .collect();
+
+        let workers = workers.max(1);
+        let mut shards: Vec<Vec<RegionLocalState>> = vec![Vec::new(); workers];
+        for (i, state) in region_states.into_iter().enumerate() {
+            shards[i % workers].push(state);
+        }
+
+        let start = start.to_vec();
+        let end = end.to_vec();
+        let mut receivers = Vec::with_capacity(workers);
+        for shard in shards {
+            if shard.is_empty() {
+                continue;
+            }
+            let (tx, rx) = crossbeam::channel::bounded(SCAN_MVCC_CHANNEL_CAPACITY);
+            let tablet_reg = self.tablet_reg.clone();
+            let start = start.clone();
+            let end = end.clone();
+            std::thread::Builder::new()
+                .name("scan-mvcc-worker".to_string())
+                .spawn(move || {
+                    for region_state in shard {
+                        let region = region_state.get_region().clone();
+                        let Some((s, e)) = range_in_region((&start, &end), &region) else {
+                            continue;
+                        };
+                        let mut tablet_cache =
+                            match get_tablet_cache(&tablet_reg, region.get_id(), Some(region_state))
+                            {
+                                Ok(cache) => cache,
+                                Err(err) => {
+                                    let _ = tx.send(Err(raftstore::Error::Other(
+                                        format!("{:?}", err).into(),
+                                    )));
+                                    continue;
+                                }
+                            };
+                        let tablet = tablet_cache.latest().unwrap();
+                        let s = if s.is_empty() { None } else { Some(data_key(s)) };
+                        let e = if e.is_empty() { None } else { Some(data_key(e)) };
+                        let scanner = MvccInfoScanner::new(
+                            |cf, opts| tablet.iterator_opt(cf, opts).map_err(|e| box_err!(e)),
+                            s.as_deref(),
+                            e.as_deref(),
+                            MvccInfoCollector::default(),
+                        );
+                        let mut scanner = match scanner {
+                            Ok(scanner) => scanner,
+                            Err(e) => {
+                                let _ = tx.send(Err(box_err!(e)));
+                                continue;
+                            }
+                        };
+                        loop {
+                            match scanner.next_item() {
+                                Ok(Some(item)) => {
+                                    if tx.send(Ok(item)).is_err() {
+                                        // Receiver was dropped, e.g. the
+                                        // caller hit `limit` and stopped
+                                        // draining us.
+                                        return;
+                                    }
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    let _ = tx.send(Err(e));
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                })
+                .unwrap();
+            receivers.push(rx);
+        }
+
+        Ok(ParallelMvccInfoIterator::new(receivers, limit as usize))
+    }
+
+    // Collects the `Normal` regions overlapping `[start, end)`, along with
+    // the per-region clipped bounds `compact` needs to pass to
+    // `compact_range_cf_opt`. Shared between the sequential `compact` and
+    // `compact_with_concurrency`.
+    fn collect_compactions(
+        &self,
+        start: &[u8],
+        end: &[u8],
+    ) -> Vec<(u64, Option<Vec<u8>>, Option<Vec<u8>>, RegionLocalState)> {
+        let mut compactions = vec![];
+        self.raft_engine
+            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
+                let region_state = self
+                    .raft_engine
+                    .get_region_state(region_id, u64::MAX)
+                    .unwrap()
+                    .unwrap();
+                if region_state.state != PeerState::Normal {
+                    return Ok(());
+                }
+
+                if let Some((start_key, end_key)) =
+                    range_in_region((start, end), region_state.get_region())
+                {
+                    let start = if start_key.is_empty() {
+                        None
+                    } else {
+                        Some(data_key(start_key))
+                    };
+                    let end = if end_key.is_empty() {
+                        None
+                    } else {
+                        Some(data_key(end_key))
+                    };
+                    compactions.push((region_id, start, end, region_state));
+                };
+
+                Ok(())
+            })
+            .unwrap();
+        compactions
+    }
+
+    /// Like `compact`, but dispatches the collected per-region compactions
+    /// across a bounded pool of `concurrency` worker threads instead of one
+    /// at a time, and reports per-region failures instead of aborting the
+    /// whole batch on the first one. Each tablet is independent, so the only
+    /// invariant shared across workers is `set_exclusive_manual_compaction(false)`,
+    /// which every call already sets.
+    pub fn compact_with_concurrency(
+        &self,
+        db: DbType,
+        cf: &str,
+        start: &[u8],
+        end: &[u8],
+        threads: u32,
+        concurrency: usize,
+        bottommost: BottommostLevelCompaction,
+    ) -> Result<Vec<(u64, Error)>> {
+        validate_db_and_cf(db, cf)?;
+        if db == DbType::Raft {
+            return Err(box_err!("Get raft db is not allowed"));
         }
+
+        let compactions = self.collect_compactions(start, end);
+        let pending = Mutex::new(compactions.into_iter());
+        let errors = Mutex::new(Vec::new());
+
+        std::thread::scope(|scope| {
+            for _ in 0..concurrency.max(1) {
+                scope.spawn(|| {
+                    loop {
+                        let next = pending.lock().unwrap().next();
+                        let Some((region_id, start_key, end_key, region_state)) = next else {
+                            break;
+                        };
+                        if let Err(e) = self.compact_one_region(
+                            cf,
+                            threads,
+                            bottommost,
+                            region_id,
+                            start_key,
+                            end_key,
+                            region_state,
+                        ) {
+                            errors.lock().unwrap().push((region_id, e));
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok(errors.into_inner().unwrap())
+    }
+
+    fn compact_one_region(
+        &self,
+        cf: &str,
+        threads: u32,
+        bottommost: BottommostLevelCompaction,
+        region_id: u64,
+        start_key: Option<Vec<u8>>,
+        end_key: Option<Vec<u8>>,
+        region_state: RegionLocalState,
+    ) -> Result<()> {
+        let mut tablet_cache = get_tablet_cache(&self.tablet_reg, region_id, Some(region_state))?;
+        let talbet = tablet_cache.latest().unwrap();
+        info!("Debugger starts manual compact"; "talbet" => ?talbet, "cf" => cf);
+        let mut opts = CompactOptions::new();
+        opts.set_max_subcompactions(threads as i32);
+        opts.set_exclusive_manual_compaction(false);
+        opts.set_bottommost_level_compaction(bottommost.0);
+        let handle = box_try!(get_cf_handle(talbet.as_inner(), cf));
+        talbet.as_inner().compact_range_cf_opt(
+            handle,
+            &opts,
+            start_key.as_ref().map(|k| k.as_bytes()),
+            end_key.as_ref().map(|k| k.as_bytes()),
+        );
+        info!("Debugger finishes manual compact"; "region_id" => region_id, "cf" => cf);
+        Ok(())
     }
 
     /// Set regions to tombstone by manual, and apply other status(such as
@@ -238,48 +825,480 @@ impl<ER: RaftEngine> DebuggerImplV2<ER> {
         let mut lb = self.raft_engine.log_batch(regions.len());
         let mut errors = Vec::with_capacity(regions.len());
         for region_id in regions {
-            let mut region_state = match self
-                .raft_engine
-                .get_region_state(region_id, u64::MAX)
-                .map_err(|e| box_err!(e))
-                .and_then(|s| s.ok_or_else(|| Error::Other("Can't find RegionLocalState".into())))
-            {
-                Ok(region_state) => region_state,
-                Err(e) => {
-                    errors.push((region_id, e));
-                    continue;
+            match self.plan_region_tombstone_by_id(region_id) {
+                Ok(Some(transition)) => {
+                    box_try!(lb.put_region_state(
+                        region_id,
+                        transition.applied_index,
+                        &transition.after
+                    ));
+                }
+                Ok(None) => {}
+                Err(e) => errors.push((region_id, e)),
+            }
+        }
+
+        if errors.is_empty() {
+            box_try!(self.raft_engine.consume(&mut lb, true));
+        }
+        Ok(errors)
+    }
+
+    // Computes the `RegionLocalState` transition `set_region_tombstone_by_id`
+    // would apply to `region_id`, without writing it. Returns `None` if the
+    // region is already a tombstone, same as the apply path's skip.
+    fn plan_region_tombstone_by_id(&self, region_id: u64) -> Result<Option<TombstoneTransition>> {
+        let region_state = self
+            .raft_engine
+            .get_region_state(region_id, u64::MAX)
+            .map_err(|e| box_err!(e))
+            .and_then(|s| s.ok_or_else(|| Error::Other("Can't find RegionLocalState".into())))?;
+        let apply_state = self
+            .raft_engine
+            .get_apply_state(region_id, u64::MAX)
+            .map_err(|e| box_err!(e))
+            .and_then(|s| s.ok_or_else(|| Error::Other("Can't find RaftApplyState".into())))?;
+
+        if region_state.get_state() == PeerState::Tombstone {
+            info!("skip {} because it's already tombstone", region_id);
+            return Ok(None);
+        }
+
+        let mut after = region_state.clone();
+        after.set_state(PeerState::Tombstone);
+
+        Ok(Some(TombstoneTransition {
+            region_id,
+            before: region_state,
+            after,
+            applied_index: apply_state.get_applied_index(),
+        }))
+    }
+
+    /// Computes the exact `RegionLocalState` transition (`Normal` or
+    /// whatever it currently is, to `Tombstone`) that
+    /// `set_region_tombstone_by_id` would apply to each of `regions`,
+    /// without writing anything. Lets an operator review a batch of
+    /// tombstone operations before committing to them.
+    pub fn dry_run_set_region_tombstone_by_id(
+        &self,
+        regions: Vec<u64>,
+    ) -> Result<(Vec<TombstoneTransition>, Vec<(u64, Error)>)> {
+        let mut transitions = Vec::with_capacity(regions.len());
+        let mut errors = vec![];
+        for region_id in regions {
+            match self.plan_region_tombstone_by_id(region_id) {
+                Ok(Some(transition)) => transitions.push(transition),
+                Ok(None) => {}
+                Err(e) => errors.push((region_id, e)),
+            }
+        }
+        Ok((transitions, errors))
+    }
+
+    /// Same as `set_region_tombstone_by_id`, except the prior
+    /// `RegionLocalState` of every region actually written is snapshotted
+    /// into the returned `TombstoneTransaction`, which can undo the whole
+    /// batch with `rollback` if a caller downstream decides the tombstone
+    /// shouldn't have gone through. Like the non-transactional version,
+    /// nothing is written at all if any region in the batch fails to plan.
+    pub fn apply_region_tombstone_transaction(
+        &self,
+        regions: Vec<u64>,
+    ) -> Result<(TombstoneTransaction<'_, ER>, Vec<(u64, Error)>)> {
+        let mut lb = self.raft_engine.log_batch(regions.len());
+        let mut snapshots = Vec::with_capacity(regions.len());
+        let mut errors = vec![];
+        for region_id in regions {
+            match self.plan_region_tombstone_by_id(region_id) {
+                Ok(Some(transition)) => {
+                    box_try!(lb.put_region_state(
+                        region_id,
+                        transition.applied_index,
+                        &transition.after
+                    ));
+                    snapshots.push((region_id, transition.before));
                 }
+                Ok(None) => {}
+                Err(e) => errors.push((region_id, e)),
+            }
+        }
+
+        if errors.is_empty() {
+            box_try!(self.raft_engine.consume(&mut lb, true));
+        }
+
+        Ok((
+            TombstoneTransaction {
+                debugger: self,
+                snapshots,
+            },
+            errors,
+        ))
+    }
+
+    // Drops every MVCC version committed after `version` from a single
+    // region's tablet. Scans CF_WRITE/CF_DEFAULT/CF_LOCK grouped by user key
+    // via `MvccInfoScanner`, batching the deletes so we don't hold an
+    // unbounded `WriteBatch` for tablets with a lot of stale history.
+    fn reset_region_to_version(
+        &self,
+        region_id: u64,
+        region_state: RegionLocalState,
+        version: u64,
+    ) -> Result<()> {
+        let mut tablet_cache = get_tablet_cache(&self.tablet_reg, region_id, Some(region_state))?;
+        let tablet = tablet_cache.latest().unwrap();
+
+        let mut scanner = MvccInfoScanner::new(
+            |cf, opts| tablet.iterator_opt(cf, opts).map_err(|e| box_err!(e)),
+            None,
+            None,
+            MvccInfoCollector::default(),
+        )
+        .map_err(|e| -> Error { box_err!(e) })?;
+
+        let mut wb = tablet.write_batch();
+        loop {
+            let (key, info) = match scanner.next_item() {
+                Ok(Some(item)) => item,
+                Ok(None) => break,
+                Err(e) => return Err(box_err!(e)),
             };
+            let encoded_key = Key::from_encoded_slice(&key);
 
-            let apply_state = match self
-                .raft_engine
-                .get_apply_state(region_id, u64::MAX)
-                .map_err(|e| box_err!(e))
-                .and_then(|s| s.ok_or_else(|| Error::Other("Can't find RaftApplyState".into())))
-            {
-                Ok(apply_state) => apply_state,
-                Err(e) => {
-                    errors.push((region_id, e));
+            for write in info.get_writes() {
+                if write.get_commit_ts() <= version {
                     continue;
                 }
+                let write_key = encoded_key.clone().append_ts(write.get_commit_ts().into());
+                box_try!(wb.delete_cf(CF_WRITE, write_key.as_encoded()));
+                if write.get_short_value().is_empty() {
+                    let default_key = encoded_key.clone().append_ts(write.get_start_ts().into());
+                    box_try!(wb.delete_cf(CF_DEFAULT, default_key.as_encoded()));
+                }
+            }
+
+            if info.has_lock() && info.get_lock().get_start_ts() > version {
+                box_try!(wb.delete_cf(CF_LOCK, encoded_key.as_encoded()));
+            }
+
+            if wb.count() >= RESET_TO_VERSION_BATCH_SIZE {
+                box_try!(wb.write());
+                wb.clear();
+            }
+        }
+
+        if !wb.is_empty() {
+            box_try!(wb.write());
+        }
+        Ok(())
+    }
+
+    /// Dumps `region_id`'s data in each of `cfs` into its own SST file under
+    /// `out_dir`, so operators can pull a single region off a broken node
+    /// and load or diff it elsewhere without copying the whole tablet. CFs
+    /// with no data in the region are skipped. Returns the written file
+    /// paths.
+    pub fn dump_region_to_sst(
+        &self,
+        region_id: u64,
+        cfs: &[&str],
+        out_dir: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let region_state = match self.raft_engine.get_region_state(region_id, u64::MAX) {
+            Ok(Some(region_state)) => {
+                if region_state.get_state() != PeerState::Normal {
+                    return Err(Error::NotFound(format!(
+                        "region {:?} has been deleted",
+                        region_id
+                    )));
+                }
+                region_state
+            }
+            Ok(None) => return Err(Error::NotFound(format!("none region {:?}", region_id))),
+            Err(e) => return Err(box_err!(e)),
+        };
+
+        let region = region_state.get_region().clone();
+        let (start_key, end_key) = range_in_region((&[], &[]), &region).unwrap();
+        let start_key = data_key(start_key);
+        let end_key = keys::data_end_key(end_key);
+
+        let mut tablet_cache = get_tablet_cache(&self.tablet_reg, region_id, Some(region_state))?;
+        let tablet = tablet_cache.latest().unwrap();
+
+        box_try!(std::fs::create_dir_all(out_dir));
+
+        let mut paths = vec![];
+        for &cf in cfs {
+            let sst_path = out_dir.join(format!("{}_{}.sst", region_id, cf));
+            let mut writer: RocksSstWriter = box_try!(
+                RocksSstWriterBuilder::new()
+                    .set_db(tablet)
+                    .set_cf(cf)
+                    .build(sst_path.to_str().unwrap())
+            );
+
+            let mut has_data = false;
+            box_try!(tablet.scan(cf, &start_key, &end_key, false, |k, v| {
+                writer.put(k, v).map_err(|e| box_err!(e))?;
+                has_data = true;
+                Ok(true)
+            }));
+
+            if !has_data {
+                continue;
+            }
+            box_try!(writer.finish());
+            paths.push(sst_path);
+        }
+
+        Ok(paths)
+    }
+
+    /// Recovers `RegionLocalState` entries for tablets that still exist on
+    /// disk under the tablet registry but whose metadata in `raft_engine`
+    /// was lost or corrupted. For every tablet directory, derives a
+    /// best-effort `[start_key, end_key)` from the minimum/maximum user
+    /// keys actually present and writes back a `Normal` `RegionLocalState`
+    /// with the observed `tablet_index`. Overlapping candidates are not
+    /// silently merged: the lower `region_id` is kept and the conflict is
+    /// reported so an operator can resolve it by hand.
+    pub fn rebuild_region_states_from_tablets(&self) -> Result<Vec<RebuildConflict>> {
+        let mut candidates = vec![];
+        for entry in box_try!(std::fs::read_dir(self.tablet_reg.tablet_root())) {
+            let entry = box_try!(entry);
+            let Some((region_id, tablet_index)) =
+                parse_tablet_dir_name(&entry.file_name().to_string_lossy())
+            else {
+                continue;
             };
 
-            if region_state.get_state() == PeerState::Tombstone {
-                info!("skip {} because it's already tombstone", region_id);
+            let ctx = TabletContext::with_infinite_region(region_id, Some(tablet_index));
+            let mut tablet_cache = match self.tablet_reg.load(ctx, false) {
+                Ok(cache) => cache,
+                Err(_) => continue,
+            };
+            let tablet = tablet_cache.latest().unwrap();
+            let Some((min_key, max_key)) = tablet_key_bounds(tablet)? else {
                 continue;
+            };
+
+            let start_key = min_key[DATA_PREFIX_KEY.len()..].to_vec();
+            // `max_key` is the last key we actually observed; the region's
+            // end key must be exclusive, so push one past it.
+            let mut end_key = max_key[DATA_PREFIX_KEY.len()..].to_vec();
+            end_key.push(0);
+
+            candidates.push((region_id, tablet_index, start_key, end_key));
+        }
+        candidates.sort_by_key(|c| c.0);
+
+        let mut conflicts = vec![];
+        let mut accepted: Vec<(u64, u64, Vec<u8>, Vec<u8>)> = vec![];
+        'candidates: for candidate in candidates {
+            for kept in &accepted {
+                let overlap_start = larger_key(&candidate.2, &kept.2, false);
+                let overlap_end = smaller_key(&candidate.3, &kept.3, true);
+                if overlap_start < overlap_end {
+                    conflicts.push(RebuildConflict {
+                        kept_region_id: kept.0,
+                        dropped_region_id: candidate.0,
+                    });
+                    continue 'candidates;
+                }
             }
-            region_state.set_state(PeerState::Tombstone);
-            box_try!(lb.put_region_state(
-                region_id,
-                apply_state.get_applied_index(),
-                &region_state
+            accepted.push(candidate);
+        }
+
+        let mut lb = self.raft_engine.log_batch(accepted.len());
+        for (region_id, tablet_index, start_key, end_key) in &accepted {
+            let mut region = metapb::Region::default();
+            region.set_id(*region_id);
+            region.set_start_key(start_key.clone());
+            region.set_end_key(end_key.clone());
+            region.mut_region_epoch().set_version(1);
+            region.mut_region_epoch().set_conf_ver(1);
+
+            let mut region_state = RegionLocalState::default();
+            region_state.set_state(PeerState::Normal);
+            region_state.set_tablet_index(*tablet_index);
+            region_state.set_region(region);
+
+            box_try!(lb.put_region_state(*region_id, *tablet_index, &region_state));
+        }
+        box_try!(self.raft_engine.consume(&mut lb, true));
+
+        Ok(conflicts)
+    }
+
+    /// Reads back `region_id`'s raft log state without mutating anything,
+    /// and classifies "overwrite compacted entries" style corruption: the
+    /// entry at `truncated_index`, which should have been GC'd, still being
+    /// readable, or a gap between `truncated_index` and the first entry
+    /// actually present. Entries below `truncated_index` are not probed, so
+    /// lingering ones there are only caught when the entry at
+    /// `truncated_index` is present too. `first_index`/`last_index` reflect
+    /// what was observed on disk, which may disagree with `RaftLocalState`.
+    pub fn check_raft_log(&self, region_id: u64) -> Result<RaftLogReport> {
+        let raft_state = box_try!(self.raft_engine.get_raft_state(region_id))
+            .ok_or_else(|| Error::NotFound(format!("raft state for region {}", region_id)))?;
+        let apply_state = box_try!(self.raft_engine.get_apply_state(region_id, u64::MAX))
+            .ok_or_else(|| Error::NotFound(format!("apply state for region {}", region_id)))?;
+
+        let last_index = raft_state.get_last_index();
+        let truncated_index = apply_state.get_truncated_state().get_index();
+        let applied_index = apply_state.get_applied_index();
+
+        let overwriting_compacted = truncated_index > 0
+            && box_try!(self.raft_engine.get_entry(region_id, truncated_index)).is_some();
+
+        let expect_first_index = truncated_index + 1;
+        let mut first_index = expect_first_index;
+        while first_index <= last_index
+            && box_try!(self.raft_engine.get_entry(region_id, first_index)).is_none()
+        {
+            first_index += 1;
+        }
+
+        let conflict = if overwriting_compacted {
+            Some(RaftLogAnomaly::OverwritingCompacted)
+        } else if last_index >= expect_first_index && first_index != expect_first_index {
+            Some(RaftLogAnomaly::IndexGap)
+        } else {
+            None
+        };
+
+        Ok(RaftLogReport {
+            region_id,
+            first_index: first_index.min(last_index + 1),
+            last_index,
+            truncated_index,
+            applied_index,
+            conflict,
+        })
+    }
+
+    /// Repairs the corruption `check_raft_log` detects: deletes any
+    /// entries lingering at or below `truncated_index`, then clamps
+    /// `RaftLocalState.last_index` down to the highest entry that's
+    /// actually contiguous from `applied_index` so the state stops
+    /// promising entries that aren't there. Never deletes an entry above
+    /// `applied_index`; the entry at `applied_index` is deleted only when it
+    /// is also the `truncated_index`. Refuses to touch a region whose peer is
+    /// still `Normal` on this store unless `force` is set, since rewriting
+    /// a live peer's log can desync it from its leader.
+    pub fn recover_raft_log(&self, region_id: u64, force: bool) -> Result<RaftLogReport> {
+        let region_state = box_try!(self.raft_engine.get_region_state(region_id, u64::MAX))
+            .ok_or_else(|| Error::NotFound(format!("region state for region {}", region_id)))?;
+        if !force && region_state.get_state() == PeerState::Normal {
+            return Err(Error::Other(
+                format!(
+                    "region {} still has a live peer, pass force to recover anyway",
+                    region_id
+                )
+                .into(),
             ));
         }
 
-        if errors.is_empty() {
-            box_try!(self.raft_engine.consume(&mut lb, true));
+        let report = self.check_raft_log(region_id)?;
+        if report.conflict.is_none() {
+            return Ok(report);
         }
-        Ok(errors)
+        if report.truncated_index > report.applied_index {
+            return Err(Error::Other(
+                format!(
+                    "region {} has truncated_index {} above applied_index {}, refusing to gc \
+                     since that would drop entries the state machine hasn't applied yet",
+                    region_id, report.truncated_index, report.applied_index
+                )
+                .into(),
+            ));
+        }
+
+        let mut lb = self.raft_engine.log_batch(1);
+        if report.truncated_index > 0 {
+            box_try!(
+                self.raft_engine
+                    .gc(region_id, 0, report.truncated_index + 1, &mut lb)
+            );
+        }
+
+        let mut last_index = report.applied_index.min(report.last_index);
+        while last_index < report.last_index
+            && box_try!(self.raft_engine.get_entry(region_id, last_index + 1)).is_some()
+        {
+            last_index += 1;
+        }
+
+        let mut raft_state = box_try!(self.raft_engine.get_raft_state(region_id))
+            .ok_or_else(|| Error::NotFound(format!("raft state for region {}", region_id)))?;
+        raft_state.set_last_index(last_index);
+        box_try!(RaftLogBatch::put_raft_state(&mut lb, region_id, &raft_state));
+        box_try!(self.raft_engine.consume(&mut lb, true));
+
+        self.check_raft_log(region_id)
+    }
+
+    /// Scans every region in the raft engine and cross-checks
+    /// `truncated_index <= applied_index <= last_index`, tombstoned
+    /// regions not leaving log entries behind, and every non-tombstone
+    /// region still having an apply state at all. Anomalies are reported
+    /// to `on_anomaly` as they're found rather than collected into a
+    /// `Vec`, so a multi-terabyte engine can be scanned without holding
+    /// every region's state in memory at once.
+    pub fn scan_region_consistency(
+        &self,
+        mut on_anomaly: impl FnMut(u64, RaftLogAnomaly),
+    ) -> Result<()> {
+        self.raft_engine
+            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
+                let is_tombstone = matches!(
+                    self.raft_engine.get_region_state(region_id, u64::MAX),
+                    Ok(Some(ref s)) if s.get_state() == PeerState::Tombstone
+                );
+
+                let apply_state = match self.raft_engine.get_apply_state(region_id, u64::MAX) {
+                    Ok(Some(apply_state)) => apply_state,
+                    _ => {
+                        if !is_tombstone {
+                            on_anomaly(region_id, RaftLogAnomaly::MissingApplyState);
+                        }
+                        return Ok(());
+                    }
+                };
+
+                let last_index = self
+                    .raft_engine
+                    .get_raft_state(region_id)
+                    .ok()
+                    .flatten()
+                    .map_or(0, |s| s.get_last_index());
+                let truncated_index = apply_state.get_truncated_state().get_index();
+                let applied_index = apply_state.get_applied_index();
+
+                if is_tombstone {
+                    if matches!(self.raft_engine.get_entry(region_id, last_index), Ok(Some(_))) {
+                        on_anomaly(region_id, RaftLogAnomaly::TombstoneWithEntries);
+                    }
+                    return Ok(());
+                }
+
+                if truncated_index > applied_index || applied_index > last_index {
+                    on_anomaly(region_id, RaftLogAnomaly::IndexGap);
+                } else if truncated_index > 0
+                    && matches!(
+                        self.raft_engine.get_entry(region_id, truncated_index),
+                        Ok(Some(_))
+                    )
+                {
+                    on_anomaly(region_id, RaftLogAnomaly::OverwritingCompacted);
+                }
+
+                Ok(())
+            })
+            .map_err(|e| box_err!(e))
     }
 }
 
@@ -340,8 +1359,21 @@ fn set_region_tombstone<ER: RaftEngine>(
 impl<ER: RaftEngine> Debugger for DebuggerImplV2<ER> {
     fn get(&self, db: DbType, cf: &str, key: &[u8]) -> Result<Vec<u8>> {
         validate_db_and_cf(db, cf)?;
-        let region_state =
-            find_region_state_by_key(&self.raft_engine, &key[DATA_PREFIX_KEY.len()..])?;
+        let inner_key = &key[DATA_PREFIX_KEY.len()..];
+        let route_table = self.region_route_table();
+        let region_state = seek_region(inner_key, &route_table)
+            .filter(|state| check_key_in_region(inner_key, state.get_region()).is_ok())
+            .ok_or_else(|| Error::NotFound(format!("Not found region containing {:?}", key)))?;
+        // The route table is cached, so re-read the region's state and refuse
+        // one that has stopped being `Normal` since the table was built.
+        let region_id = region_state.get_region().get_id();
+        match box_try!(self.raft_engine.get_region_state(region_id, u64::MAX)) {
+            Some(state) if state.get_state() == PeerState::Normal => {}
+            _ => {
+                self.invalidate_region_route_table();
+                return Err(Error::NotFound(format!("region {:?} has been deleted", region_id)));
+            }
+        }
         let mut tablet_cache = get_tablet_cache(
             &self.tablet_reg,
             region_state.get_region().get_id(),
@@ -427,26 +1459,14 @@ impl<ER: RaftEngine> Debugger for DebuggerImplV2<ER> {
             ));
         }
 
-        let mut region_states = vec![];
-        self.raft_engine
-            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
-                let region_state = self
-                    .raft_engine
-                    .get_region_state(region_id, u64::MAX)
-                    .unwrap()
-                    .unwrap();
-                if region_state.state == PeerState::Normal {
-                    region_states.push(region_state);
-                }
-                Ok(())
-            })
-            .unwrap();
-
-        region_states.sort_by(|r1, r2| {
-            r1.get_region()
-                .get_start_key()
-                .cmp(r2.get_region().get_start_key())
-        });
+        // Only regions whose range actually overlaps [start, end) can
+        // contribute rows; skip the rest so we don't open a tablet per
+        // region on stores with thousands of them.
+        let region_states: Vec<_> = self
+            .region_route_table()
+            .into_iter()
+            .filter(|state| range_in_region((start, end), state.get_region()).is_some())
+            .collect();
 
         MvccInfoIteratorV2::new(
             region_states,
@@ -470,55 +1490,18 @@ impl<ER: RaftEngine> Debugger for DebuggerImplV2<ER> {
         if db == DbType::Raft {
             return Err(box_err!("Get raft db is not allowed"));
         }
-        let mut compactions = vec![];
-        self.raft_engine
-            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
-                let region_state = self
-                    .raft_engine
-                    .get_region_state(region_id, u64::MAX)
-                    .unwrap()
-                    .unwrap();
-                if region_state.state != PeerState::Normal {
-                    return Ok(());
-                }
-
-                if let Some((start_key, end_key)) =
-                    range_in_region((start, end), region_state.get_region())
-                {
-                    let start = if start_key.is_empty() {
-                        None
-                    } else {
-                        Some(data_key(start_key))
-                    };
-                    let end = if end_key.is_empty() {
-                        None
-                    } else {
-                        Some(data_key(end_key))
-                    };
-                    compactions.push((region_id, start, end, region_state));
-                };
-
-                Ok(())
-            })
-            .unwrap();
+        let compactions = self.collect_compactions(start, end);
 
         for (region_id, start_key, end_key, region_state) in compactions {
-            let mut tablet_cache =
-                get_tablet_cache(&self.tablet_reg, region_id, Some(region_state))?;
-            let talbet = tablet_cache.latest().unwrap();
-            info!("Debugger starts manual compact"; "talbet" => ?talbet, "cf" => cf);
-            let mut opts = CompactOptions::new();
-            opts.set_max_subcompactions(threads as i32);
-            opts.set_exclusive_manual_compaction(false);
-            opts.set_bottommost_level_compaction(bottommost.0);
-            let handle = box_try!(get_cf_handle(talbet.as_inner(), cf));
-            talbet.as_inner().compact_range_cf_opt(
-                handle,
-                &opts,
-                start_key.as_ref().map(|k| k.as_bytes()),
-                end_key.as_ref().map(|k| k.as_bytes()),
-            );
-            info!("Debugger finishes manual compact"; "db" => ?db, "cf" => cf);
+            self.compact_one_region(
+                cf,
+                threads,
+                bottommost,
+                region_id,
+                start_key,
+                end_key,
+                region_state,
+            )?;
         }
 
         Ok(())
@@ -557,12 +1540,103 @@ impl<ER: RaftEngine> Debugger for DebuggerImplV2<ER> {
             })
     }
 
-    fn get_region_properties(&self, _region_id: u64) -> Result<Vec<(String, String)>> {
-        unimplemented!()
+    fn get_region_properties(&self, region_id: u64) -> Result<Vec<(String, String)>> {
+        let region_state = match self.raft_engine.get_region_state(region_id, u64::MAX) {
+            Ok(Some(region_state)) => {
+                if region_state.get_state() != PeerState::Normal {
+                    return Err(Error::NotFound(format!(
+                        "region {:?} has been deleted",
+                        region_id
+                    )));
+                }
+                region_state
+            }
+            Ok(None) => return Err(Error::NotFound(format!("none region {:?}", region_id))),
+            Err(e) => return Err(box_err!(e)),
+        };
+        let region = region_state.get_region().clone();
+        let start_key = keys::data_key(region.get_start_key());
+        let end_key = keys::data_end_key(region.get_end_key());
+
+        let mut tablet_cache = get_tablet_cache(&self.tablet_reg, region.id, Some(region_state))?;
+        let tablet = tablet_cache.latest().unwrap();
+
+        let mut props = vec![];
+        for cf in [CF_DEFAULT, CF_LOCK, CF_WRITE] {
+            let mut size = 0;
+            let mut num_entries = 0;
+            let mut num_versions = 0;
+            let mut num_rows = 0;
+            let mut num_deletes = 0;
+            let mut last_row: Option<Vec<u8>> = None;
+            box_try!(tablet.scan(cf, &start_key, &end_key, false, |k, v| {
+                size += k.len() + v.len();
+                num_entries += 1;
+                if cf == CF_WRITE {
+                    let write = box_try!(WriteRef::parse(v));
+                    if write.write_type == WriteType::Delete {
+                        num_deletes += 1;
+                    }
+                    num_versions += 1;
+                    let row = &k[..k.len() - 8];
+                    if last_row.as_deref() != Some(row) {
+                        num_rows += 1;
+                        last_row = Some(row.to_vec());
+                    }
+                }
+                Ok(true)
+            }));
+
+            let handle = box_try!(get_cf_handle(tablet.as_inner(), cf));
+            let cf_meta = tablet.as_inner().get_column_family_metadata_cf(handle);
+            for (level, level_meta) in cf_meta.get_levels().iter().enumerate() {
+                props.push((
+                    format!("{}.num_files_at_level{}", cf, level),
+                    level_meta.get_files().len().to_string(),
+                ));
+            }
+
+            props.push((format!("{}.size", cf), size.to_string()));
+            props.push((format!("{}.num_entries", cf), num_entries.to_string()));
+            if cf == CF_WRITE {
+                props.push(("mvcc.num_versions".to_owned(), num_versions.to_string()));
+                props.push(("mvcc.num_rows".to_owned(), num_rows.to_string()));
+                props.push(("mvcc.num_deletes".to_owned(), num_deletes.to_string()));
+            }
+        }
+
+        let approximate_size: usize = props
+            .iter()
+            .filter(|(name, _)| name.ends_with(".size"))
+            .map(|(_, value)| value.parse::<usize>().unwrap())
+            .sum();
+        props.push((
+            "region.approximate_size".to_owned(),
+            approximate_size.to_string(),
+        ));
+
+        Ok(props)
     }
 
-    fn reset_to_version(&self, _version: u64) {
-        unimplemented!()
+    fn reset_to_version(&self, version: u64) {
+        let _ = self
+            .raft_engine
+            .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
+                let region_state = match self.raft_engine.get_region_state(region_id, u64::MAX) {
+                    Ok(Some(region_state)) if region_state.get_state() == PeerState::Normal => {
+                        region_state
+                    }
+                    _ => return Ok(()),
+                };
+                if let Err(e) = self.reset_region_to_version(region_id, region_state, version) {
+                    warn!(
+                        "reset_to_version failed for region";
+                        "region_id" => region_id,
+                        "err" => ?e,
+                    );
+                }
+                Ok(())
+            });
     }
 
     fn set_kv_statistics(&mut self, s: Option<Arc<RocksStatistics>>) {
@@ -659,36 +1733,6 @@ fn range_in_region<'a>(
     }
 }
 
-fn find_region_state_by_key<ER: RaftEngine>(
-    raft_engine: &ER,
-    key: &[u8],
-) -> Result<RegionLocalState> {
-    let mut region_ids = vec![];
-    raft_engine
-        .for_each_raft_group::<raftstore::Error, _>(&mut |region_id| {
-            region_ids.push(region_id);
-            Ok(())
-        })
-        .unwrap();
-
-    for region_id in region_ids {
-        if let Ok(Some(region_state)) = raft_engine.get_region_state(region_id, u64::MAX) {
-            let region = region_state.get_region();
-            if check_key_in_region(key, region).is_ok() {
-                if region_state.get_state() != PeerState::Normal {
-                    break;
-                }
-                return Ok(region_state);
-            }
-        }
-    }
-
-    Err(Error::NotFound(format!(
-        "Not found region containing {:?}",
-        key
-    )))
-}
-
 fn get_tablet_cache(
     tablet_reg: &TabletRegistry<RocksEngine>,
     region_id: u64,
@@ -712,6 +1756,40 @@ fn get_tablet_cache(
     }
 }
 
+// Tablet directories are named `<region_id>_<tablet_index>` (see
+// `TabletRegistry::tablet_path`); parse that back out of a directory name
+// found under the registry's root, e.g. when reconstructing metadata for
+// tablets whose `RegionLocalState` was lost.
+fn parse_tablet_dir_name(name: &str) -> Option<(u64, u64)> {
+    let (region_id, tablet_index) = name.rsplit_once('_')?;
+    Some((region_id.parse().ok()?, tablet_index.parse().ok()?))
+}
+
+// Returns the smallest and largest on-disk (data-prefixed) keys across
+// CF_DEFAULT/CF_LOCK/CF_WRITE in `tablet`, or `None` if it has no data in
+// any of them.
+fn tablet_key_bounds(tablet: &RocksEngine) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut min_key: Option<Vec<u8>> = None;
+    let mut max_key: Option<Vec<u8>> = None;
+    for cf in [CF_DEFAULT, CF_LOCK, CF_WRITE] {
+        let mut iter = box_try!(tablet.iterator(cf));
+        if box_try!(iter.seek_to_first()) {
+            let k = iter.key().to_vec();
+            if min_key.as_deref().map_or(true, |m| k.as_slice() < m) {
+                min_key = Some(k);
+            }
+        }
+        if box_try!(iter.seek_to_last()) {
+            let k = iter.key().to_vec();
+            if max_key.as_deref().map_or(true, |m| k.as_slice() > m) {
+                max_key = Some(k);
+            }
+        }
+    }
+
+    Ok(min_key.zip(max_key))
+}
+
 // `key1` and `key2` should both be start_key or end_key.
 fn smaller_key<'a>(key1: &'a [u8], key2: &'a [u8], end_key: bool) -> &'a [u8] {
     if end_key && key1.is_empty() {
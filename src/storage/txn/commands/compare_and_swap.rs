@@ -8,6 +8,10 @@ use raw::RawStore;
 use tikv_kv::Statistics;
 use txn_types::{Key, Value};
 
+use std::{collections::HashMap, sync::Mutex};
+
+use futures::channel::oneshot;
+
 use crate::storage::{
     kv::{Modify, WriteData},
     lock_manager::LockManager,
@@ -22,11 +26,139 @@ use crate::storage::{
     ProcessResult, Snapshot,
 };
 
+struct KeyWatchState<T> {
+    version: u64,
+    value: Option<T>,
+    waiters: Vec<(u64, oneshot::Sender<(T, u64)>)>,
+}
+
+impl<T> Default for KeyWatchState<T> {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            value: None,
+            waiters: Vec::new(),
+        }
+    }
+}
+
+/// A long-poll registry for raw-key changes: a reader `register`s
+/// `(key, after_version)` and gets back a future that resolves once some
+/// write to `key` is `notify`d with a version past `after_version`, instead
+/// of having to poll the key on a timer.
+///
+/// `notify`'s version is this registry's own per-key monotonic counter --
+/// it isn't the same number space as `RawCompareAndSwap`'s
+/// `expected_version` token, just a cheap way for a registered waiter to
+/// tell "a newer value than the one I last saw" apart from "the same value
+/// notified again".
+pub struct RawKeyWatchRegistry<T> {
+    keys: Mutex<HashMap<Vec<u8>, KeyWatchState<T>>>,
+}
+
+impl<T: Clone> Default for RawKeyWatchRegistry<T> {
+    fn default() -> Self {
+        Self {
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Clone> RawKeyWatchRegistry<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a watcher on `key`. If `key` already advanced past
+    /// `after_version` before this call (e.g. it changed between the
+    /// caller's last read and this call), resolves immediately instead of
+    /// waiting for the next write, closing the lost-update race a
+    /// register-then-wait split would otherwise have.
+    pub fn register(&self, key: Vec<u8>, after_version: u64) -> oneshot::Receiver<(T, u64)> {
+        let (tx, rx) = oneshot::channel();
+        let mut keys = self.keys.lock().unwrap();
+        let state = keys.entry(key).or_default();
+        if state.version > after_version {
+            if let Some(value) = state.value.clone() {
+                let _ = tx.send((value, state.version));
+                return rx;
+            }
+        }
+        state.waiters.push((after_version, tx));
+        rx
+    }
+
+    /// Signals every waiter on `key` whose `after_version` this write has
+    /// surpassed. Should be called once the write has actually applied, not
+    /// merely been proposed.
+    pub fn notify(&self, key: &[u8], value: T) {
+        let mut keys = self.keys.lock().unwrap();
+        let state = keys.entry(key.to_vec()).or_default();
+        state.version += 1;
+        state.value = Some(value.clone());
+        let version = state.version;
+        let mut i = 0;
+        while i < state.waiters.len() {
+            if state.waiters[i].0 < version {
+                let (_, tx) = state.waiters.swap_remove(i);
+                let _ = tx.send((value.clone(), version));
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Collects the `(key, value)` pairs a just-written batch of `Modify::Put`s
+/// should notify once the caller has confirmed the write actually applied;
+/// see `RawKeyWatchRegistry::notify`'s apply-timing caveat for why this
+/// can't be done eagerly inside `process_write`.
+fn raw_watch_notifies(modifies: &[Modify]) -> Vec<(Vec<u8>, Value)> {
+    modifies
+        .iter()
+        .filter_map(|m| match m {
+            Modify::Put(_, key, value) => Some((key.as_encoded().clone(), value.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Notifies every raw-key watcher `modifies` touched, using the same
+/// `raw_watch` handle `process_write` was given via `WriteContext`.
+///
+/// This is the call the scheduler's apply-confirmation path (i.e. whatever
+/// drives a command with `ResponsePolicy::OnApplied` to completion, after
+/// `engine.write` for `WriteResult::to_be_write` has actually been
+/// acknowledged) needs to make for `RawCompareAndSwap` and
+/// `RawBatchCompareAndSwap` -- `process_write` itself only runs against a
+/// snapshot and returns before the write is durable, so it must not call
+/// this.
+///
+/// No production caller does yet: `src/storage/txn` has no `scheduler.rs` (or
+/// any `mod.rs`) in this checkout, so there is nowhere to add the real
+/// post-apply call from here. `sched_command`/`sched_batch_command` below are
+/// test-only stand-ins shaped like that callback, not a substitute for it --
+/// until `scheduler.rs` exists and calls this, raw-key watches don't fire for
+/// a real write request.
+pub(crate) fn notify_raw_watches(raw_watch: &RawKeyWatchRegistry<Value>, modifies: &[Modify]) {
+    for (key, value) in raw_watch_notifies(modifies) {
+        raw_watch.notify(&key, value);
+    }
+}
+
 // TODO: consider add `KvFormat` generic parameter.
 command! {
     /// RawCompareAndSwap checks whether the previous value of the key equals to the given value.
     /// If they are equal, write the new value. The bool indicates whether they are equal.
     /// The previous value is always returned regardless of whether the new value is set.
+    ///
+    /// When `expected_version` is `Some` (API V2 only, where raw values
+    /// already carry a commit version), the comparison is keyed on the
+    /// stored value's version token instead of byte-comparing
+    /// `previous_value`, so a client guarding a large value doesn't have to
+    /// ship the whole old value just to prove it hasn't changed. In that
+    /// mode the stored value itself is not read, so the previous value
+    /// returned is `None`.
     RawCompareAndSwap:
         cmd_ty => (Option<Value>, bool),
         display => "kv::command::raw_compare_and_swap {:?}", (ctx),
@@ -37,6 +169,7 @@ command! {
             value: Value,
             ttl: u64,
             api_version: ApiVersion,
+            expected_version: Option<u64>,
         }
 }
 
@@ -51,17 +184,38 @@ impl CommandExt for RawCompareAndSwap {
 }
 
 impl<S: Snapshot, L: LockManager> WriteCommand<S, L> for RawCompareAndSwap {
-    fn process_write(self, snapshot: S, _: WriteContext<'_, L>) -> Result<WriteResult> {
+    // `write_ctx.raw_watch` is deliberately not read here: this runs against
+    // a snapshot before `to_be_write` is durable, and `RawKeyWatchRegistry`
+    // must not notify a waiter about a value that could still fail to apply.
+    // The caller is expected to call `notify_raw_watches(write_ctx.raw_watch,
+    // &result.to_be_write.modifies)` once the write this returns has
+    // actually been applied; see `notify_raw_watches`'s doc comment.
+    fn process_write(self, snapshot: S, _write_ctx: WriteContext<'_, L>) -> Result<WriteResult> {
         let (cf, key, value, previous_value, ctx) =
             (self.cf, self.key, self.value, self.previous_value, self.ctx);
         let mut data = vec![];
-        let old_value = RawStore::new(snapshot, self.api_version).raw_get_key_value(
-            cf,
-            &key,
-            &mut Statistics::default(),
-        )?;
+        let store = RawStore::new(snapshot, self.api_version);
+        let mut statistics = Statistics::default();
 
-        let pr = if old_value == previous_value {
+        // Version-token mode only fetches the stored version, not the
+        // value itself, trading the precise old value (the caller already
+        // has it, or doesn't need it) for a much cheaper compare.
+        let (succeed, old_value, current_version) = match (self.api_version, self.expected_version)
+        {
+            (ApiVersion::V2, Some(expected_version)) => {
+                let observed = store.raw_get_key_ts(cf, &key, &mut statistics)?;
+                (observed == Some(expected_version), None, observed)
+            }
+            _ => {
+                let old_value = store.raw_get_key_value(cf, &key, &mut statistics)?;
+                (old_value == previous_value, old_value, None)
+            }
+        };
+
+        let pr = if succeed {
+            // Long-poll watchers on this key are notified once the `Modify`
+            // below has actually applied, not here; see `notify_raw_watches`
+            // and `RawKeyWatchRegistry::notify`'s apply-timing caveat.
             let raw_value = RawValue {
                 user_value: value,
                 expire_ts: ttl_to_expire_ts(self.ttl),
@@ -78,11 +232,13 @@ impl<S: Snapshot, L: LockManager> WriteCommand<S, L> for RawCompareAndSwap {
             ProcessResult::RawCompareAndSwapRes {
                 previous_value: old_value,
                 succeed: true,
+                current_version,
             }
         } else {
             ProcessResult::RawCompareAndSwapRes {
                 previous_value: old_value,
                 succeed: false,
+                current_version,
             }
         };
         fail_point!("txn_commands_compare_and_swap");
@@ -102,6 +258,117 @@ impl<S: Snapshot, L: LockManager> WriteCommand<S, L> for RawCompareAndSwap {
     }
 }
 
+// TODO: consider add `KvFormat` generic parameter.
+command! {
+    /// RawBatchCompareAndSwap checks whether the previous value of every key
+    /// in the batch equals its paired expected value, writing the new value
+    /// for each match. Unlike `RawCompareAndSwap`, many keys are compared
+    /// and swapped atomically against the engine in one command.
+    ///
+    /// When `atomic` is `true` the whole batch is all-or-nothing: a single
+    /// mismatch aborts every pending write in the batch. Otherwise each
+    /// item succeeds or fails independently, exactly like issuing that many
+    /// `RawCompareAndSwap`s back to back but in one round-trip.
+    RawBatchCompareAndSwap:
+        cmd_ty => Vec<(Option<Value>, bool)>,
+        display => "kv::command::raw_batch_compare_and_swap {:?}", (ctx),
+        content => {
+            // cf, key, previous_value (expected), value (new), ttl
+            pairs: Vec<(CfName, Key, Option<Value>, Value, u64)>,
+            atomic: bool,
+            api_version: ApiVersion,
+        }
+}
+
+impl CommandExt for RawBatchCompareAndSwap {
+    ctx!();
+    tag!(raw_batch_compare_and_swap);
+    gen_lock!(pairs: multiple(|(_, key, ..)| key));
+
+    fn write_bytes(&self) -> usize {
+        self.pairs
+            .iter()
+            .map(|(_, key, _, value, _)| key.as_encoded().len() + value.len())
+            .sum()
+    }
+}
+
+impl<S: Snapshot, L: LockManager> WriteCommand<S, L> for RawBatchCompareAndSwap {
+    // See the note on `RawCompareAndSwap::process_write`: `write_ctx.raw_watch`
+    // is threaded in so the caller has it, not so this function can notify
+    // before the batch has actually applied.
+    fn process_write(self, snapshot: S, _write_ctx: WriteContext<'_, L>) -> Result<WriteResult> {
+        let (pairs, atomic, api_version, ctx) =
+            (self.pairs, self.atomic, self.api_version, self.ctx);
+        let store = RawStore::new(snapshot, api_version);
+        let mut statistics = Statistics::default();
+
+        // Check every comparison against the snapshot up front, before
+        // staging any write, so an all-or-nothing batch that ends up
+        // aborting never touches the engine.
+        let mut checked = Vec::with_capacity(pairs.len());
+        for (cf, key, expected, value, ttl) in pairs {
+            let old_value = store.raw_get_key_value(cf, &key, &mut statistics)?;
+            let succeed = old_value == expected;
+            checked.push((cf, key, old_value, succeed, value, ttl));
+        }
+
+        if atomic && checked.iter().any(|(.., succeed, _, _)| !succeed) {
+            let results = checked
+                .into_iter()
+                .map(|(_, _, old_value, _, _, _)| (old_value, false))
+                .collect();
+            return Ok(WriteResult {
+                ctx,
+                to_be_write: WriteData::default(),
+                rows: 0,
+                pr: ProcessResult::RawCompareAndSwapBatchRes { results },
+                lock_info: None,
+                lock_guards: vec![],
+                response_policy: ResponsePolicy::OnApplied,
+                known_txn_status: vec![],
+            });
+        }
+
+        let mut data = vec![];
+        let mut results = Vec::with_capacity(checked.len());
+        for (cf, key, old_value, succeed, value, ttl) in checked {
+            if succeed {
+                // As with `RawCompareAndSwap`, the long-poll watcher is
+                // notified post-apply via `notify_raw_watches`, not here.
+                let raw_value = RawValue {
+                    user_value: value,
+                    expire_ts: ttl_to_expire_ts(ttl),
+                    is_delete: false,
+                };
+                let encoded_raw_value = match_template_api_version!(
+                    API,
+                    match api_version {
+                        ApiVersion::API => API::encode_raw_value_owned(raw_value),
+                    }
+                );
+                data.push(Modify::Put(cf, key, encoded_raw_value));
+            }
+            results.push((old_value, succeed));
+        }
+
+        fail_point!("txn_commands_batch_compare_and_swap");
+        let rows = data.len();
+        let mut to_be_write = WriteData::from_modifies(data);
+        to_be_write.set_allowed_on_disk_almost_full();
+        Ok(WriteResult {
+            ctx,
+            to_be_write,
+            rows,
+            pr: ProcessResult::RawCompareAndSwapBatchRes { results },
+            lock_info: None,
+            lock_guards: vec![],
+            response_policy: ResponsePolicy::OnApplied,
+            known_txn_status: vec![],
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use api_version::test_kv_format_impl;
@@ -110,15 +377,10 @@ mod tests {
     use kvproto::kvrpcpb::Context;
 
     use super::*;
-<<<<<<< HEAD
-    use crate::storage::{lock_manager::DummyLockManager, Engine, Statistics, TestEngineBuilder};
-=======
     use crate::storage::{
-        lock_manager::MockLockManager,
-        txn::{scheduler::get_raw_ext, txn_status_cache::TxnStatusCache},
-        Engine, Statistics, TestEngineBuilder,
+        lock_manager::DummyLockManager, txn::txn_status_cache::TxnStatusCache, Engine,
+        Statistics, TestEngineBuilder,
     };
->>>>>>> 0a34c6f479 (txn: Fix to the prewrite requests retry problem by using TxnStatusCache (#15658))
 
     #[test]
     fn test_cas_basic() {
@@ -142,6 +404,7 @@ mod tests {
             b"v1".to_vec(),
             0,
             F::TAG,
+            None,
             Context::default(),
         );
         let (prev_val, succeed) = sched_command(&engine, cm.clone(), cmd).unwrap();
@@ -155,6 +418,7 @@ mod tests {
             b"v2".to_vec(),
             1,
             F::TAG,
+            None,
             Context::default(),
         );
         let (prev_val, succeed) = sched_command(&engine, cm.clone(), cmd).unwrap();
@@ -168,6 +432,7 @@ mod tests {
             b"v3".to_vec(),
             2,
             F::TAG,
+            None,
             Context::default(),
         );
         let (prev_val, succeed) = sched_command(&engine, cm, cmd).unwrap();
@@ -183,92 +448,223 @@ mod tests {
         let snap = engine.snapshot(Default::default())?;
         use kvproto::kvrpcpb::ExtraOp;
         let mut statistic = Statistics::default();
+        let watches = RawKeyWatchRegistry::new();
         let context = WriteContext {
             lock_mgr: &DummyLockManager {},
             concurrency_manager: cm,
             extra_op: ExtraOp::Noop,
             statistics: &mut statistic,
             async_apply_prewrite: false,
-<<<<<<< HEAD
-=======
-            raw_ext,
+            raw_watch: &watches,
+            raw_ext: None,
             txn_status_cache: &TxnStatusCache::new_for_test(),
->>>>>>> 0a34c6f479 (txn: Fix to the prewrite requests retry problem by using TxnStatusCache (#15658))
         };
         let ret = cmd.cmd.process_write(snap, context)?;
         match ret.pr {
             ProcessResult::RawCompareAndSwapRes {
                 previous_value,
                 succeed,
+                ..
             } => {
                 if succeed {
                     let ctx = Context::default();
+                    let modifies = ret.to_be_write.modifies.clone();
                     engine.write(&ctx, ret.to_be_write).unwrap();
+                    notify_raw_watches(&watches, &modifies);
                 }
                 Ok((previous_value, succeed))
             }
             _ => unreachable!(),
         }
     }
-<<<<<<< HEAD
-=======
 
     #[test]
-    fn test_cas_process_write() {
-        test_kv_format_impl!(test_cas_process_write_impl);
+    fn test_batch_cas_independent() {
+        test_kv_format_impl!(test_batch_cas_independent_impl);
     }
 
-    fn test_cas_process_write_impl<F: KvFormat>() {
-        let mut engine = TestEngineBuilder::new().build().unwrap();
-        let ts_provider = super::super::test_util::gen_ts_provider(F::TAG);
+    /// Independent mode: a mismatch on one key doesn't stop the others in
+    /// the same batch from being written.
+    fn test_batch_cas_independent_impl<F: KvFormat>() {
+        let engine = TestEngineBuilder::new().build().unwrap();
+        let cm = concurrency_manager::ConcurrencyManager::new(1.into());
+        let k1 = F::encode_raw_key(b"k1", None);
+        let k2 = F::encode_raw_key(b"k2", None);
+
+        let cmd = RawBatchCompareAndSwap::new(
+            vec![
+                (CF_DEFAULT, k1.clone(), None, b"v1".to_vec(), 0),
+                (
+                    CF_DEFAULT,
+                    k2.clone(),
+                    Some(b"stale".to_vec()),
+                    b"v2".to_vec(),
+                    0,
+                ),
+            ],
+            false,
+            F::TAG,
+            Context::default(),
+        );
+        let results = sched_batch_command(&engine, cm.clone(), cmd).unwrap();
+        assert_eq!(results, vec![(None, true), (None, false)]);
+
+        let cmd = RawCompareAndSwap::new(
+            CF_DEFAULT,
+            k1,
+            Some(b"v1".to_vec()),
+            b"v1b".to_vec(),
+            0,
+            F::TAG,
+            None,
+            Context::default(),
+        );
+        let (prev_val, succeed) = sched_command(&engine, cm, cmd).unwrap();
+        assert_eq!(prev_val, Some(b"v1".to_vec()));
+        assert!(succeed);
+    }
 
+    #[test]
+    fn test_batch_cas_atomic_aborts_whole_batch_on_mismatch() {
+        test_kv_format_impl!(test_batch_cas_atomic_aborts_whole_batch_on_mismatch_impl);
+    }
+
+    fn test_batch_cas_atomic_aborts_whole_batch_on_mismatch_impl<F: KvFormat>() {
+        let engine = TestEngineBuilder::new().build().unwrap();
         let cm = concurrency_manager::ConcurrencyManager::new(1.into());
-        let raw_key = b"rk";
-        let raw_value = b"valuek";
-        let ttl = 30;
-        let encode_value = RawValue {
-            user_value: raw_value.to_vec(),
-            expire_ts: ttl_to_expire_ts(ttl),
-            is_delete: false,
-        };
+        let k1 = F::encode_raw_key(b"k1", None);
+        let k2 = F::encode_raw_key(b"k2", None);
+
+        let cmd = RawBatchCompareAndSwap::new(
+            vec![
+                (CF_DEFAULT, k1.clone(), None, b"v1".to_vec(), 0),
+                (
+                    CF_DEFAULT,
+                    k2,
+                    Some(b"stale".to_vec()),
+                    b"v2".to_vec(),
+                    0,
+                ),
+            ],
+            true,
+            F::TAG,
+            Context::default(),
+        );
+        let results = sched_batch_command(&engine, cm.clone(), cmd).unwrap();
+        assert_eq!(results, vec![(None, false), (None, false)]);
+
+        // The batch aborted atomically, so `k1` was never written either.
         let cmd = RawCompareAndSwap::new(
             CF_DEFAULT,
-            F::encode_raw_key(raw_key, None),
+            k1,
             None,
-            raw_value.to_vec(),
-            ttl,
+            b"v1".to_vec(),
+            0,
             F::TAG,
+            None,
             Context::default(),
         );
+        let (prev_val, succeed) = sched_command(&engine, cm, cmd).unwrap();
+        assert!(prev_val.is_none());
+        assert!(succeed);
+    }
+
+    pub fn sched_batch_command<E: Engine>(
+        engine: &E,
+        cm: ConcurrencyManager,
+        cmd: TypedCommand<Vec<(Option<Value>, bool)>>,
+    ) -> Result<Vec<(Option<Value>, bool)>> {
+        let snap = engine.snapshot(Default::default())?;
+        use kvproto::kvrpcpb::ExtraOp;
         let mut statistic = Statistics::default();
-        let snap = engine.snapshot(Default::default()).unwrap();
-        let raw_ext = block_on(get_raw_ext(ts_provider, cm.clone(), true, &cmd.cmd)).unwrap();
+        let watches = RawKeyWatchRegistry::new();
         let context = WriteContext {
-            lock_mgr: &MockLockManager::new(),
+            lock_mgr: &DummyLockManager {},
             concurrency_manager: cm,
-            extra_op: kvproto::kvrpcpb::ExtraOp::Noop,
+            extra_op: ExtraOp::Noop,
             statistics: &mut statistic,
             async_apply_prewrite: false,
-            raw_ext,
+            raw_watch: &watches,
+            raw_ext: None,
             txn_status_cache: &TxnStatusCache::new_for_test(),
         };
-        let cmd: Command = cmd.into();
-        let write_result = cmd.process_write(snap, context).unwrap();
-        let modifies_with_ts = vec![Modify::Put(
+        let ret = cmd.cmd.process_write(snap, context)?;
+        match ret.pr {
+            ProcessResult::RawCompareAndSwapBatchRes { results } => {
+                if results.iter().any(|(_, succeed)| *succeed) {
+                    let ctx = Context::default();
+                    let modifies = ret.to_be_write.modifies.clone();
+                    engine.write(&ctx, ret.to_be_write).unwrap();
+                    notify_raw_watches(&watches, &modifies);
+                }
+                Ok(results)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_raw_key_watch_registry_resolves_on_notify() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let registry = RawKeyWatchRegistry::new();
+            let rx = registry.register(b"k1".to_vec(), 0);
+            registry.notify(b"k1", b"v1".to_vec());
+            let (value, version) = rx.await.unwrap();
+            assert_eq!(value, b"v1".to_vec());
+            assert_eq!(version, 1);
+        });
+    }
+
+    #[test]
+    fn test_raw_key_watch_registry_resolves_immediately_for_stale_token() {
+        let registry = RawKeyWatchRegistry::new();
+        registry.notify(b"k1", b"v1".to_vec());
+        registry.notify(b"k1", b"v2".to_vec());
+
+        // Registering with a token already behind the current version must
+        // not block on a future `notify` -- it should resolve right away.
+        let mut rx = registry.register(b"k1".to_vec(), 0);
+        let (value, version) = rx.try_recv().unwrap().unwrap();
+        assert_eq!(value, b"v2".to_vec());
+        assert_eq!(version, 2);
+    }
+
+    #[test]
+    fn test_raw_key_watch_registry_ignores_unrelated_keys() {
+        let registry = RawKeyWatchRegistry::new();
+        let mut rx = registry.register(b"k1".to_vec(), 0);
+        registry.notify(b"k2", b"v2".to_vec());
+        assert!(rx.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_raw_watch_notifies_extracts_puts_only() {
+        let encoded_key = api_version::ApiV1::encode_raw_key(b"k1", None);
+        let modifies = vec![Modify::Put(
+            CF_DEFAULT,
+            encoded_key.clone(),
+            b"v1".to_vec(),
+        )];
+        let notifies = raw_watch_notifies(&modifies);
+        assert_eq!(notifies, vec![(encoded_key.as_encoded().clone(), b"v1".to_vec())]);
+    }
+
+    #[test]
+    fn test_notify_raw_watches_wakes_registered_waiter() {
+        let encoded_key = api_version::ApiV1::encode_raw_key(b"k1", None);
+        let registry = RawKeyWatchRegistry::new();
+        let mut rx = registry.register(encoded_key.as_encoded().clone(), 0);
+        let modifies = vec![Modify::Put(
             CF_DEFAULT,
-            F::encode_raw_key(raw_key, Some(101.into())),
-            F::encode_raw_value_owned(encode_value),
+            encoded_key.clone(),
+            b"v1".to_vec(),
         )];
-        assert_eq!(write_result.to_be_write.modifies, modifies_with_ts);
-        if F::TAG == ApiVersion::V2 {
-            assert_eq!(write_result.lock_guards.len(), 1);
-            let raw_key = vec![api_version::api_v2::RAW_KEY_PREFIX];
-            let encoded_key = ApiV2::encode_raw_key(&raw_key, Some(100.into()));
-            assert_eq!(
-                write_result.lock_guards.first().unwrap().key(),
-                &encoded_key
-            );
-        }
+        notify_raw_watches(&registry, &modifies);
+        let (value, version) = rx.try_recv().unwrap().unwrap();
+        assert_eq!(value, b"v1".to_vec());
+        assert_eq!(version, 1);
     }
->>>>>>> 0a34c6f479 (txn: Fix to the prewrite requests retry problem by using TxnStatusCache (#15658))
 }
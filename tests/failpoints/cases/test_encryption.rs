@@ -44,6 +44,16 @@ fn test_file_dict_file_record_corrupted() {
     assert_eq!(file_dict.files.len(), 1);
 }
 
+// The best-effort recovery mode requested here -- skip mid-log corruption,
+// rebuild from the last clean snapshot plus the intact tail, and return a
+// report of applied/skipped records -- requires changing
+// `FileDictionaryFile::recovery` itself, which lives in the `encryption`
+// crate. That crate is not part of this checkout (there is no
+// `components/encryption` directory), so the change cannot be made, and
+// cannot be tested, from this file. Unimplemented here; re-scope with
+// whoever filed the request once the `encryption` crate is available in
+// this tree.
+
 fn create_file_info(id: u64, method: EncryptionMethod) -> FileInfo {
     FileInfo {
         key_id: id,
@@ -74,3 +84,12 @@ fn test_kms_provider_temporary_unavailable() {
     let pt_decrypt = backend.decrypt_content(&encrypted_content).unwrap();
     assert_eq!(pt_decrypt, pt);
 }
+
+// The configurable retry/backoff policy and circuit breaker requested here
+// belong in the `encryption` crate (`encryption::kms`'s KMS backend), which
+// is not part of this checkout -- there is no `components/encryption`
+// directory to add `kms.rs` changes to, and no way to exercise a real
+// circuit breaker from this test binary alone. Unimplemented here;
+// implementing this for real needs the `encryption` crate source and should
+// be re-scoped with whoever filed the request once that crate is available
+// in this tree.
@@ -357,4 +357,65 @@ fn test_storage_do_not_update_txn_status_cache_on_write_error() {
     must_kv_have_locks(&client, ctx, 29, b"k2", b"k3", &[(b"k2", Op::Put, 20, 20)]);
     fail::remove(cache_hit_fp);
 }
+
+#[test]
+fn test_stale_read_push_async_commit() {
+    let (_cluster, client, ctx) = must_new_cluster_and_kv_client();
+
+    let ts = 100;
+    let read_ts = ts + 10;
+    let k1 = b"k1";
+    let v1 = b"v1";
+
+    // Mirrors test_scan_lock_push_async_commit: pause the prewrite right
+    // after it acquires the memory lock, so a stale read racing it here is
+    // guaranteed to land in the same window a real race would, and its
+    // max_ts bump has to be visible to the still-pending prewrite.
+    fail::cfg("before-set-lock-in-memory", "pause").unwrap();
+    let client1 = client.clone();
+    let ctx1 = ctx.clone();
+    let handle = std::thread::spawn(move || {
+        let mut prewrite = PrewriteRequest::default();
+        prewrite.set_context(ctx1);
+        let mut mutation = Mutation::default();
+        mutation.set_op(Op::Put);
+        mutation.set_key(k1.to_vec());
+        mutation.set_value(v1.to_vec());
+        prewrite.set_mutations(vec![mutation].into());
+        prewrite.set_primary_lock(k1.to_vec());
+        prewrite.set_start_version(ts);
+        prewrite.set_lock_ttl(1000);
+        prewrite.set_use_async_commit(true);
+
+        let resp = client1.kv_prewrite(&prewrite).unwrap();
+        assert!(!resp.has_region_error());
+        assert_eq!(resp.get_errors(), &[]);
+        // The stale read below observed (or raced) state at `read_ts`; if the
+        // invariant holds, min_commit_ts must clear it so a repeat read at
+        // `read_ts` can never miss this write.
+        assert!(resp.min_commit_ts > read_ts);
+    });
+
+    // Wait for the prewrite to acquire the memlock.
+    std::thread::sleep(Duration::from_millis(200));
+
+    let mut get_req = GetRequest::default();
+    get_req.set_context(ctx.clone());
+    get_req.set_key(k1.to_vec());
+    get_req.version = read_ts;
+    get_req.mut_context().set_stale_read(true);
+    let resp = client.kv_get(&get_req).unwrap();
+    assert!(resp.region_error.is_none());
+
+    fail::remove("before-set-lock-in-memory");
+    handle.join().unwrap();
+
+    let mut commit = CommitRequest::default();
+    commit.set_context(ctx);
+    commit.set_start_version(ts);
+    commit.set_commit_version(ts + 1000);
+    commit.set_keys(vec![k1.to_vec()].into());
+    let resp = client.kv_commit(&commit).unwrap();
+    assert!(!resp.has_region_error());
+}
 >>>>>>> 0a34c6f479 (txn: Fix to the prewrite requests retry problem by using TxnStatusCache (#15658))